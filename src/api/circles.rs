@@ -0,0 +1,22 @@
+use actix_web::{HttpResponse, Result, error};
+use actix_multipart::Multipart;
+
+use crate::api::form;
+use crate::cv::circles;
+
+// Circles detected in every frame of the upload.
+pub async fn circle_stats(payload: Multipart) -> Result<HttpResponse> {
+    let form = form::read_form(payload).await?;
+
+    if form.files.is_empty() {
+        return Err(error::ErrorBadRequest("Missing video upload"));
+    }
+    let options = circles::CircleOptions::from_params(&form.fields).map_err(error::ErrorBadRequest)?;
+    let workers = form.workers()?;
+
+    let (_, frames) = form
+        .run(move |form| circles::circle_stats(&form.files[0], &options, workers))
+        .await?;
+
+    Ok(HttpResponse::Ok().json(frames))
+}
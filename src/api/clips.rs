@@ -0,0 +1,112 @@
+use actix_files::NamedFile;
+use actix_web::{HttpRequest, HttpResponse, Responder, Result, error};
+use actix_multipart::Multipart;
+use anyhow::Error;
+use serde::Serialize;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::api::form;
+use crate::cv::{clips, helper};
+
+#[derive(Serialize)]
+struct ClipIndex {
+    duration: f32,
+    segments: Vec<clips::Segment>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    files: Option<Vec<String>>,
+}
+
+async fn read_request(payload: Multipart) -> Result<(form::Form, clips::ClipOptions)> {
+    let form = form::read_form(payload).await?;
+
+    if form.files.is_empty() {
+        return Err(error::ErrorBadRequest("Missing video upload"));
+    }
+    let options = clips::ClipOptions::from_params(&form.fields).map_err(error::ErrorBadRequest)?;
+
+    Ok((form, options))
+}
+
+fn detect(video_path: &str, options: &clips::ClipOptions) -> Result<ClipIndex, Error> {
+    let scores = clips::score_motion(video_path, options.scoring)?;
+
+    Ok(ClipIndex {
+        duration: scores.len() as f32 / helper::FRAME_RATE,
+        segments: clips::find_segments(&scores, helper::FRAME_RATE, options),
+        files: None,
+    })
+}
+
+// Start and end timestamps of the active parts of the upload.
+pub async fn clip_index(payload: Multipart) -> Result<HttpResponse> {
+    let (form, options) = read_request(payload).await?;
+
+    let (_, index) = form.run(move |form| detect(&form.files[0], &options)).await?;
+
+    Ok(HttpResponse::Ok().json(index))
+}
+
+// Returns only the active parts of the upload, either concatenated into one
+// video or zipped up as separate clips with their timestamps in `index.json`.
+// Without any motion there is nothing to send but the empty index.
+pub async fn extract_clips(req: HttpRequest, payload: Multipart) -> Result<HttpResponse> {
+    let (form, options) = read_request(payload).await?;
+
+    let as_zip = match form.field("output").unwrap_or("concat") {
+        "concat" => false,
+        "zip" => true,
+        other => return Err(error::ErrorBadRequest(format!("Unknown clip output '{}'", other))),
+    };
+
+    let (_form, (index, output_path)) = form
+        .run(move |form| {
+            let mut index = detect(&form.files[0], &options)?;
+            let clip_paths = clips::extract_clips(&form.files[0], &index.segments, form.dir.path())?;
+            if clip_paths.is_empty() {
+                return Ok((index, None));
+            }
+
+            let output_path = form.path(if as_zip { "motion_clips.zip" } else { "motion.mp4" });
+            if as_zip {
+                index.files = Some(clip_paths.iter().map(|path| file_name(path)).collect());
+                write_zip(&clip_paths, &serde_json::to_string(&index)?, &output_path)?;
+            } else {
+                helper::concat_clips(&clip_paths, &output_path)?;
+            }
+            Ok((index, Some(output_path)))
+        })
+        .await?;
+
+    match output_path {
+        Some(output_path) => Ok(NamedFile::open(output_path)?.respond_to(&req).map_into_boxed_body()),
+        None => Ok(HttpResponse::Ok().json(index)),
+    }
+}
+
+fn file_name(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .map_or(path.to_string(), |name| name.to_string_lossy().to_string())
+}
+
+fn write_zip(clip_paths: &[String], index_json: &str, output_path: &str) -> Result<(), Error> {
+    let mut zip = ZipWriter::new(File::create(output_path)?);
+    // The clips are already compressed video, deflating them again gains nothing.
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+
+    zip.start_file("index.json", options)?;
+    zip.write_all(index_json.as_bytes())?;
+
+    for clip in clip_paths {
+        zip.start_file(file_name(clip), options)?;
+        zip.write_all(&fs::read(clip)?)?;
+    }
+
+    zip.finish()?;
+
+    Ok(())
+}
@@ -0,0 +1,27 @@
+use actix_web::{HttpResponse, Result, error};
+use actix_multipart::Multipart;
+use image::ImageFormat;
+use std::io::Cursor;
+
+use crate::api::form;
+use crate::cv::features;
+
+// ORB matches between the first two uploaded images, drawn side by side.
+pub async fn match_features(payload: Multipart) -> Result<HttpResponse> {
+    let form = form::read_form(payload).await?;
+
+    if form.files.len() < 2 {
+        return Err(error::ErrorBadRequest("Expected two image uploads"));
+    }
+    let options = features::OrbOptions::from_params(&form.fields).map_err(error::ErrorBadRequest)?;
+
+    let (_, png) = form
+        .run(move |form| {
+            let mut png = Cursor::new(Vec::new());
+            features::match_images(&form.files[0], &form.files[1], &options)?.write_to(&mut png, ImageFormat::Png)?;
+            Ok(png.into_inner())
+        })
+        .await?;
+
+    Ok(HttpResponse::Ok().content_type("image/png").body(png))
+}
@@ -1,69 +1,115 @@
+use actix_web::{web, HttpResponse, Result, error};
+use actix_web::CustomizeResponder;
 use actix_files::NamedFile;
-use actix_web::{Result, error};
 use actix_multipart::Multipart;
-use futures_util::StreamExt;
-use tokio::io::AsyncWriteExt;
-
-use crate::cv::{sobel, grayscale, posterize};
-
-pub async fn apply_filter(mut payload: Multipart) ->  Result<NamedFile>{
-    let mut filter_type = String::new();
-    let mut file_path = String::new();
-
-    while let Some(field) = payload.next().await {
-        let mut field = field.unwrap();
-
-        let field_key = field
-            .content_disposition()
-            .unwrap()
-            .get_name()
-            .unwrap();
-
-        println!("FIELD KEY : {}", field_key);
-
-        match field_key {
-            "video" => {
-                let file_name = field
-                    .content_disposition()
-                    .ok_or_else(|| error::ErrorBadRequest("Missing content disposition"))?
-                    .get_filename()
-                    .map_or("file".to_string(), |name| name.to_string());
-                file_path = format!("./video/{}",file_name);
-                let mut file = tokio::fs::File::create(&file_path).await.unwrap();
-                while let Some(chunk) = field.next().await {
-                    let data = chunk.unwrap();
-                    file.write_all(&data).await.unwrap();
-                }
-            },
-            "filter" => {
-                println!("Inside Filter");
-                let mut filter_value = String::new();
-                while let Some(chunk) = field.next().await {
-                    let data = chunk.map_err(|e| error::ErrorInternalServerError(e))?;
-                    filter_value.push_str(&String::from_utf8_lossy(&data));
-                }
-                filter_type = filter_value;
-            }
-            _ => {}
-        }
-    }
+use anyhow::{anyhow, Error};
+
+use std::collections::HashMap;
+
+use crate::api::form;
+use crate::cv::{filter, helper, pipeline, preset, stabilize};
 
-    match filter_type.as_str(){
-        "sobel" => {
-            sobel::sobel_edge_filter(&file_path).unwrap();
-        },
-        "grayscale"=>{
-            grayscale::grayscale_filter(&file_path).unwrap();
-        },
-        "posterize"=>{
-            posterize::posterize_filter(&file_path).unwrap();
+// A preset by name, a registered filter configured by the other form
+// fields, or a pipeline such as `grayscale|gaussian:sigma=1.4|sobel`.
+// Every worker builds its own filter instance from the factory.
+fn requested_filter<'a>(presets: &'a [preset::Preset], fields: &'a HashMap<String, String>) -> Result<(Box<filter::Factory<'a>>, helper::VideoOptions), Error> {
+    let (make, video): (Box<filter::Factory>, helper::VideoOptions) = match fields.get("preset") {
+        Some(name) => {
+            let preset = preset::find(presets, name)
+                .ok_or_else(|| anyhow!("Unknown preset '{}'", name))?;
+            (Box::new(move || Ok(Box::new(preset.pipeline()?))), preset.video_options())
         }
-        _ => {}
+        None => {
+            let filter_type = fields.get("filter").map_or("", |filter_type| filter_type.as_str());
+            let make: Box<filter::Factory> = match filter::find(filter_type) {
+                Some(entry) => Box::new(|| entry.build(fields)),
+                None => Box::new(move || Ok(Box::new(pipeline::Pipeline::parse(filter_type)?))),
+            };
+            (make, helper::VideoOptions::default())
+        }
+    };
+    make()?;
+    Ok((make, video))
+}
+
+pub async fn apply_filter(presets: web::Data<Vec<preset::Preset>>, payload: Multipart) ->  Result<CustomizeResponder<NamedFile>>{
+    let form = form::read_form(payload).await?;
+
+    let (_, video) = requested_filter(&presets, &form.fields).map_err(error::ErrorBadRequest)?;
+    // The output matches the upload as an MP4 unless the preset or the form
+    // fields ask for another size, rate, container or quality.
+    let video = video.with_params(&form.fields).map_err(error::ErrorBadRequest)?;
+    let container = video.container().map_err(error::ErrorBadRequest)?;
+    let workers = form.workers()?;
+
+    if form.files.is_empty() {
+        return Err(error::ErrorBadRequest("Missing video upload"));
     }
+    let stabilize = match form.field("stabilize") {
+        Some("true") => Some(stabilize::StabilizeOptions::from_params(&form.fields).map_err(error::ErrorBadRequest)?),
+        _ => None,
+    };
+
+    let presets = presets.into_inner();
+    let (_form, output_path) = form
+        .run(move |form| {
+            let mut file_path = form.files[0].clone();
+            if let Some(options) = stabilize {
+                let stabilized_path = form.path("stabilized.mp4");
+                stabilize::stabilize_video(&file_path, &options, &video, &stabilized_path)?;
+                file_path = stabilized_path;
+            }
+
+            let (make, _) = requested_filter(&presets, &form.fields)?;
+            let output_path = form.output_path(container);
+            filter::run(&file_path, &output_path, &make, &video, workers)?;
+            Ok(output_path)
+        })
+        .await?;
+
+    // The open file outlives the job directory, which goes with the form.
+    form::send_output(&output_path, container)
+}
+
+// Runs the same filters on a single PNG, JPEG, WebP, BMP or TIFF image,
+// entirely in memory. The result is in the upload's format unless `format`
+// names another one.
+pub async fn apply_filter_image(presets: web::Data<Vec<preset::Preset>>, payload: Multipart) -> Result<HttpResponse> {
+    let form::MemoryForm { file, fields } = form::read_memory_form(payload).await?;
+
+    // Built once here so a bad filter is a 400, the job builds its own.
+    let _ = requested_filter(&presets, &fields).map_err(error::ErrorBadRequest)?;
+    let output_format = match fields.get("format") {
+        Some(name) => Some(filter::image_format(name).map_err(error::ErrorBadRequest)?),
+        None => None,
+    };
+    let upload = file.ok_or_else(|| error::ErrorBadRequest("Missing image upload"))?;
+
+    // Decoding, filtering and encoding all run on the blocking pool, only
+    // an upload that is not an image is the request's fault.
+    let (image, input_format) = web::block(move || filter::decode_image(&upload))
+        .await?
+        .map_err(error::ErrorBadRequest)?;
+    let output_format = output_format.unwrap_or(input_format);
+
+    let presets = presets.into_inner();
+    let data = web::block(move || {
+        let (make, _) = requested_filter(&presets, &fields)?;
+        let filtered = make()?.apply(&image)?;
+        filter::encode_image(&filtered, output_format)
+    })
+    .await?
+    .map_err(error::ErrorInternalServerError)?;
 
-    let file_to_send = NamedFile::open("./video/output.mp4")?;
+    Ok(HttpResponse::Ok().content_type(output_format.to_mime_type()).body(data))
+}
 
-    std::fs::remove_file("./video/output.mp4")?;
+// Every registered filter with its parameters, for the GUI dropdown.
+pub async fn list_filters() -> HttpResponse {
+    HttpResponse::Ok().json(filter::registry())
+}
 
-    return Ok(file_to_send);
-}
\ No newline at end of file
+// The presets loaded from ./presets at startup.
+pub async fn list_presets(presets: web::Data<Vec<preset::Preset>>) -> HttpResponse {
+    HttpResponse::Ok().json(presets.get_ref())
+}
@@ -0,0 +1,206 @@
+use actix_files::NamedFile;
+use actix_web::{web, Responder, Result, error};
+use actix_web::http::header;
+use actix_web::CustomizeResponder;
+use actix_multipart::{Field, Multipart};
+use anyhow::Error;
+use futures_util::StreamExt;
+use tokio::io::AsyncWriteExt;
+use std::collections::HashMap;
+use std::path::Path;
+use tempfile::TempDir;
+
+use crate::cv::helper;
+
+// Multipart upload split into the uploaded files and the remaining text
+// fields. Every request gets its own working directory holding the uploads
+// and anything the job writes next to them. It is deleted when the form is
+// dropped, whether the job succeeded, failed or panicked, so handlers have
+// to keep the form alive until they are done with its files.
+pub struct Form {
+    pub files: Vec<String>,
+    pub fields: HashMap<String, String>,
+    pub dir: TempDir,
+}
+
+impl Form {
+    // Path for a file in this job's directory.
+    pub fn path(&self, name: &str) -> String {
+        self.dir.path().join(name).to_string_lossy().to_string()
+    }
+
+    // Path the job's encoded video goes to, named for its container.
+    pub fn output_path(&self, container: &helper::Container) -> String {
+        self.path(&format!("output.{}", container.name))
+    }
+
+    pub fn field(&self, key: &str) -> Option<&str> {
+        self.fields.get(key).map(|value| value.as_str())
+    }
+
+    // Frames filtered at once on the shared pool, 0 (the default) uses one
+    // per core.
+    pub fn workers(&self) -> Result<usize> {
+        let workers = match self.field("workers") {
+            Some(workers) => workers
+                .parse()
+                .map_err(|_| error::ErrorBadRequest(format!("Invalid workers '{}'", workers)))?,
+            None => 0,
+        };
+        if workers > helper::MAX_WORKERS {
+            return Err(error::ErrorBadRequest(format!("workers must be at most {}", helper::MAX_WORKERS)));
+        }
+        Ok(workers)
+    }
+
+    // Runs a blocking job on actix's blocking thread pool. The form goes along
+    // and comes back with the result, so its directory outlives the job and
+    // anything sent from it. Job errors are server errors, the request has to
+    // be checked before the job starts.
+    pub async fn run<T, F>(self, job: F) -> Result<(Form, T)>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Form) -> Result<T, Error> + Send + 'static,
+    {
+        let (form, result) = web::block(move || {
+            let result = job(&self);
+            (self, result)
+        })
+        .await?;
+        Ok((form, result.map_err(error::ErrorInternalServerError)?))
+    }
+}
+
+// Sends an encoded video with the Content-Type of its container.
+pub fn send_output(path: &str, container: &helper::Container) -> Result<CustomizeResponder<NamedFile>> {
+    Ok(NamedFile::open(path)?.customize().insert_header((header::CONTENT_TYPE, container.content_type)))
+}
+
+// Name of a multipart field and, for an upload, its file name.
+fn field_names(field: &Field) -> Result<(String, Option<String>)> {
+    let content_disposition = field
+        .content_disposition()
+        .ok_or_else(|| error::ErrorBadRequest("Missing content disposition"))?;
+    let field_key = content_disposition
+        .get_name()
+        .ok_or_else(|| error::ErrorBadRequest("Missing field name"))?
+        .to_string();
+
+    Ok((field_key, content_disposition.get_filename().map(|name| name.to_string())))
+}
+
+// Longest text field a form takes, pipelines included.
+pub const MAX_FIELD_LENGTH: usize = 64 * 1024;
+
+async fn read_text(field: &mut Field) -> Result<String> {
+    let mut value = Vec::new();
+    while let Some(chunk) = field.next().await {
+        let data = chunk?;
+        if value.len() + data.len() > MAX_FIELD_LENGTH {
+            return Err(error::ErrorPayloadTooLarge(format!(
+                "Form fields must be at most {} KB",
+                MAX_FIELD_LENGTH / 1024
+            )));
+        }
+        value.extend_from_slice(&data);
+    }
+    Ok(String::from_utf8_lossy(&value).into_owned())
+}
+
+pub async fn read_form(mut payload: Multipart) -> Result<Form> {
+    let dir = tempfile::Builder::new().prefix("comp_vision_").tempdir()?;
+    let mut files = Vec::new();
+    let mut fields = HashMap::new();
+
+    while let Some(field) = payload.next().await {
+        let mut field = field?;
+
+        match field_names(&field)? {
+            (_, Some(file_name)) => {
+                // Uploads are numbered instead of keeping the client's name,
+                // so they can not overwrite each other or the files the job
+                // writes, nor land outside the job directory. The extension
+                // stays for decoders that go by it.
+                let extension: String = Path::new(&file_name)
+                    .extension()
+                    .map(|extension| extension.to_string_lossy().chars().filter(char::is_ascii_alphanumeric).collect())
+                    .unwrap_or_default();
+                let name = match extension.as_str() {
+                    "" => format!("upload_{}", files.len()),
+                    extension => format!("upload_{}.{}", files.len(), extension),
+                };
+                let file_path = dir.path().join(name).to_string_lossy().to_string();
+                let mut file = tokio::fs::File::create(&file_path).await?;
+                while let Some(chunk) = field.next().await {
+                    let data = chunk?;
+                    file.write_all(&data).await?;
+                }
+                files.push(file_path);
+            }
+            (field_key, None) => {
+                let value = read_text(&mut field).await?;
+                fields.insert(field_key, value);
+            }
+        }
+    }
+
+    Ok(Form { files, fields, dir })
+}
+
+// Largest request `read_memory_form` reads, all parts together.
+pub const MAX_MEMORY_UPLOAD: usize = 64 * 1024 * 1024;
+
+// A form whose upload stays in memory, for jobs such as still images that
+// need no working directory.
+pub struct MemoryForm {
+    pub file: Option<Vec<u8>>,
+    pub fields: HashMap<String, String>,
+}
+
+// Keeps the first upload and the text fields. Later uploads are read past
+// and dropped, but still count towards `MAX_MEMORY_UPLOAD`.
+pub async fn read_memory_form(mut payload: Multipart) -> Result<MemoryForm> {
+    let mut file = None;
+    let mut fields = HashMap::new();
+    let mut total = 0;
+    let too_large = || {
+        error::ErrorPayloadTooLarge(format!(
+            "Requests must be at most {} MB",
+            MAX_MEMORY_UPLOAD / (1024 * 1024)
+        ))
+    };
+
+    while let Some(field) = payload.next().await {
+        let mut field = field?;
+
+        match field_names(&field)? {
+            (_, Some(_)) => {
+                let keep = file.is_none();
+                let mut data = Vec::new();
+                while let Some(chunk) = field.next().await {
+                    let chunk = chunk?;
+                    total += chunk.len();
+                    if total > MAX_MEMORY_UPLOAD {
+                        return Err(too_large());
+                    }
+                    if keep {
+                        data.extend_from_slice(&chunk);
+                    }
+                }
+                if keep {
+                    file = Some(data);
+                }
+            }
+            (field_key, None) => {
+                let value = read_text(&mut field).await?;
+                total += value.len();
+                if total > MAX_MEMORY_UPLOAD {
+                    return Err(too_large());
+                }
+                fields.insert(field_key, value);
+            }
+        }
+    }
+
+    Ok(MemoryForm { file, fields })
+}
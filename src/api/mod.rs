@@ -1 +1,10 @@
-pub mod filter;
\ No newline at end of file
+pub mod filter;
+pub mod form;
+pub mod clips;
+pub mod scenes;
+pub mod track;
+pub mod components;
+pub mod features;
+pub mod panorama;
+pub mod circles;
+pub mod probe;
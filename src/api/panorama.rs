@@ -0,0 +1,32 @@
+use actix_web::{HttpResponse, Result, error};
+use actix_multipart::Multipart;
+use image::ImageFormat;
+use std::io::Cursor;
+
+use crate::api::form;
+use crate::cv::panorama;
+
+// Stitches every uploaded image, in upload order, into one panorama PNG.
+pub async fn stitch(payload: Multipart) -> Result<HttpResponse> {
+    let form = form::read_form(payload).await?;
+
+    if form.files.len() < 2 {
+        return Err(error::ErrorBadRequest("Expected at least two image uploads"));
+    }
+    let options = panorama::PanoramaOptions::from_params(&form.fields).map_err(error::ErrorBadRequest)?;
+
+    // Images that do not overlap enough fail to stitch, which is the
+    // request's fault rather than the server's.
+    let (form, canvas) = form.run(move |form| Ok(panorama::stitch_files(&form.files, &options))).await?;
+    let canvas = canvas.map_err(error::ErrorUnprocessableEntity)?;
+
+    let (_, png) = form
+        .run(move |_| {
+            let mut png = Cursor::new(Vec::new());
+            canvas.write_to(&mut png, ImageFormat::Png)?;
+            Ok(png.into_inner())
+        })
+        .await?;
+
+    Ok(HttpResponse::Ok().content_type("image/png").body(png))
+}
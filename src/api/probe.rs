@@ -0,0 +1,21 @@
+use actix_web::{HttpResponse, Result, error};
+use actix_multipart::Multipart;
+
+use crate::api::form;
+use crate::cv::helper;
+
+// Size, rate, length, rotation and pixel format of the upload, which the
+// filtered output keeps unless overridden.
+pub async fn probe_video(payload: Multipart) -> Result<HttpResponse> {
+    let form = form::read_form(payload).await?;
+
+    if form.files.is_empty() {
+        return Err(error::ErrorBadRequest("Missing video upload"));
+    }
+
+    // ffprobe only fails on files that are not videos.
+    let (_, probe) = form.run(|form| Ok(helper::probe(&form.files[0]))).await?;
+    let probe = probe.map_err(error::ErrorBadRequest)?;
+
+    Ok(HttpResponse::Ok().json(probe))
+}
@@ -0,0 +1,50 @@
+use actix_web::{HttpResponse, Result, error};
+use actix_multipart::Multipart;
+use image::ImageFormat;
+use std::io::Cursor;
+
+use crate::api::form;
+use crate::cv::scenes;
+
+async fn read_request(payload: Multipart) -> Result<(form::Form, scenes::SceneOptions)> {
+    let form = form::read_form(payload).await?;
+
+    if form.files.is_empty() {
+        return Err(error::ErrorBadRequest("Missing video upload"));
+    }
+    let options = scenes::SceneOptions::from_params(&form.fields).map_err(error::ErrorBadRequest)?;
+
+    Ok((form, options))
+}
+
+// Cut timestamps and one keyframe timestamp per shot.
+pub async fn scene_index(payload: Multipart) -> Result<HttpResponse> {
+    let (form, options) = read_request(payload).await?;
+
+    let (_, scenes) = form.run(move |form| scenes::detect_scenes(&form.files[0], &options)).await?;
+
+    Ok(HttpResponse::Ok().json(scenes))
+}
+
+// The keyframe of every shot laid out as a single PNG contact sheet.
+pub async fn contact_sheet(payload: Multipart) -> Result<HttpResponse> {
+    let (form, options) = read_request(payload).await?;
+
+    let columns: u32 = match form.field("columns") {
+        Some(columns) => columns.parse().map_err(error::ErrorBadRequest)?,
+        None => 4,
+    };
+
+    let (_, png) = form
+        .run(move |form| {
+            let scenes = scenes::detect_scenes(&form.files[0], &options)?;
+            let keyframes = scenes::extract_keyframes(&form.files[0], &scenes.shots)?;
+
+            let mut png = Cursor::new(Vec::new());
+            scenes::contact_sheet(&keyframes, columns).write_to(&mut png, ImageFormat::Png)?;
+            Ok(png.into_inner())
+        })
+        .await?;
+
+    Ok(HttpResponse::Ok().content_type("image/png").body(png))
+}
@@ -0,0 +1,50 @@
+use actix_files::NamedFile;
+use actix_web::{HttpResponse, Result, error};
+use actix_web::CustomizeResponder;
+use actix_multipart::Multipart;
+
+use crate::api::form;
+use crate::cv::{helper, tracker};
+
+async fn read_request(payload: Multipart) -> Result<(form::Form, tracker::TrackOptions)> {
+    let form = form::read_form(payload).await?;
+
+    if form.files.is_empty() {
+        return Err(error::ErrorBadRequest("Missing video upload"));
+    }
+    let options = tracker::TrackOptions::from_params(&form.fields).map_err(error::ErrorBadRequest)?;
+
+    Ok((form, options))
+}
+
+// Per-frame CAMShift boxes for the object inside the initial `box`.
+pub async fn track_boxes(payload: Multipart) -> Result<HttpResponse> {
+    let (form, options) = read_request(payload).await?;
+
+    let (_, track) = form
+        .run(move |form| tracker::track_video(&form.files[0], &options, &helper::VideoOptions::analysis(), None))
+        .await?;
+
+    Ok(HttpResponse::Ok().json(track))
+}
+
+// Same as `track_boxes`, but the boxes are drawn onto the returned video,
+// which matches the upload unless the form asks for another size, rate,
+// container or quality.
+pub async fn track_video(payload: Multipart) -> Result<CustomizeResponder<NamedFile>> {
+    let (form, options) = read_request(payload).await?;
+    let video = helper::VideoOptions::default()
+        .with_params(&form.fields)
+        .map_err(error::ErrorBadRequest)?;
+    let container = video.container().map_err(error::ErrorBadRequest)?;
+
+    let (_form, output_path) = form
+        .run(move |form| {
+            let output_path = form.output_path(container);
+            tracker::track_video(&form.files[0], &options, &video, Some(&output_path))?;
+            Ok(output_path)
+        })
+        .await?;
+
+    form::send_output(&output_path, container)
+}
@@ -0,0 +1,56 @@
+use crate::cv::{filter, helper, pipeline};
+use anyhow::{anyhow, Error};
+
+const USAGE: &str = "Usage:
+    comp_vision                                     start the web server
+    comp_vision filter <video> <pipeline> [output]  filter a video, output defaults to output.mp4,
+                                                    its extension picks mp4, webm, gif or apng
+        --workers <n>                               frames filtered in parallel, 0 for one per core
+    comp_vision check <pipeline>                    validate a pipeline and print it
+    comp_vision filters                             list filters and their parameters
+
+Pipelines look like 'grayscale|gaussian:sigma=1.4|sobel|threshold:otsu'.";
+
+// Runs the command line given after the program name.
+pub fn run(args: &[String]) -> Result<(), Error> {
+    let mut args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+
+    let mut workers = 0;
+    if let Some(i) = args.iter().position(|&arg| arg == "--workers") {
+        let value = args.get(i + 1).ok_or_else(|| anyhow!("--workers needs a value"))?;
+        workers = value.parse().map_err(|_| anyhow!("Invalid worker count '{}'", value))?;
+        args.drain(i..=i + 1);
+    }
+
+    match args.as_slice() {
+        ["filter", video, text] | ["filter", video, text, _] => {
+            let output = args.get(3).copied().unwrap_or("output.mp4");
+            let container = std::path::Path::new(output).extension().and_then(|extension| extension.to_str());
+            let options = helper::VideoOptions { container: container.unwrap_or_default().to_string(), ..Default::default() };
+            options.validate()?;
+            let make = || -> Result<Box<dyn filter::Filter>, Error> { Ok(Box::new(pipeline::Pipeline::parse(text)?)) };
+            // Report a bad pipeline or output before any frames are extracted.
+            make()?;
+
+            filter::run(video, output, &make, &options, workers)?;
+
+            println!("Wrote {}", output);
+        }
+        ["check", text] => {
+            let pipeline = pipeline::Pipeline::parse(text)?;
+            println!("{}", pipeline);
+        }
+        ["filters"] => {
+            for entry in filter::registry() {
+                println!("{:<12} {}", entry.name, entry.label);
+                for param in &entry.params {
+                    println!("    {:<16} {:<10} {}", param.name, param.default, param.description);
+                }
+            }
+        }
+        ["help"] | ["--help"] | ["-h"] => println!("{}", USAGE),
+        _ => return Err(anyhow!("{}", USAGE)),
+    }
+
+    Ok(())
+}
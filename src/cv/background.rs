@@ -0,0 +1,299 @@
+use image::{ImageBuffer, Luma, Rgb, RgbImage};
+
+// Mask values follow the usual background subtraction convention so masks from
+// either model can be consumed the same way downstream.
+pub const BACKGROUND: u8 = 0;
+pub const SHADOW: u8 = 127;
+pub const FOREGROUND: u8 = 255;
+
+pub type Mask = ImageBuffer<Luma<u8>, Vec<u8>>;
+
+pub trait BackgroundModel: Send {
+    // Feeds the next frame into the model and returns its foreground mask.
+    fn apply(&mut self, frame: &RgbImage) -> Mask;
+}
+
+//Shadow test from Horprasert et al.: a shadow is a darker copy of the
+//background with roughly the same chromaticity. `alpha` is the brightness
+//ratio of the pixel projected onto the background colour.
+const SHADOW_MIN_BRIGHTNESS: f32 = 0.5;
+const SHADOW_MAX_CHROMA_DIST: f32 = 0.1;
+
+fn is_shadow(pixel: [f32; 3], background: [f32; 3]) -> bool {
+    let bb = dot(background, background);
+    if bb <= f32::EPSILON {
+        return false;
+    }
+
+    let alpha = dot(pixel, background) / bb;
+    if !(SHADOW_MIN_BRIGHTNESS..1.0).contains(&alpha) {
+        return false;
+    }
+
+    let dr = pixel[0] - alpha * background[0];
+    let dg = pixel[1] - alpha * background[1];
+    let db = pixel[2] - alpha * background[2];
+    let chroma = dr * dr + dg * dg + db * db;
+
+    chroma < SHADOW_MAX_CHROMA_DIST * SHADOW_MAX_CHROMA_DIST * bb * alpha * alpha
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn to_f32(pixel: &Rgb<u8>) -> [f32; 3] {
+    [pixel.0[0] as f32, pixel.0[1] as f32, pixel.0[2] as f32]
+}
+
+pub struct RunningAverage {
+    learning_rate: f32,
+    threshold: f32,
+    detect_shadows: bool,
+    background: Vec<[f32; 3]>,
+    dimensions: (u32, u32),
+}
+
+impl RunningAverage {
+    pub fn new(learning_rate: f32, threshold: f32, detect_shadows: bool) -> RunningAverage {
+        RunningAverage {
+            learning_rate,
+            threshold,
+            detect_shadows,
+            background: Vec::new(),
+            dimensions: (0, 0),
+        }
+    }
+}
+
+impl BackgroundModel for RunningAverage {
+    fn apply(&mut self, frame: &RgbImage) -> Mask {
+        let (width, height) = frame.dimensions();
+        let mut mask = ImageBuffer::new(width, height);
+
+        // First frame (or a resolution change) seeds the background.
+        if self.dimensions != (width, height) {
+            self.background = frame.pixels().map(to_f32).collect();
+            self.dimensions = (width, height);
+            return mask;
+        }
+
+        let threshold = self.threshold * self.threshold;
+
+        for (i, (x, y, pixel)) in frame.enumerate_pixels().enumerate() {
+            let value = to_f32(pixel);
+            let background = &mut self.background[i];
+
+            let dr = value[0] - background[0];
+            let dg = value[1] - background[1];
+            let db = value[2] - background[2];
+            let dist = dr * dr + dg * dg + db * db;
+
+            let label = if dist <= threshold {
+                BACKGROUND
+            } else if self.detect_shadows && is_shadow(value, *background) {
+                SHADOW
+            } else {
+                FOREGROUND
+            };
+
+            // Foreground pixels are blended in more slowly so that objects
+            // which stop moving are only absorbed after a while.
+            let rate = if label == FOREGROUND { self.learning_rate * 0.1 } else { self.learning_rate };
+            for (b, v) in background.iter_mut().zip(value) {
+                *b += rate * (v - *b);
+            }
+
+            mask.put_pixel(x, y, Luma([label]));
+        }
+
+        mask
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Gaussian {
+    weight: f32,
+    mean: [f32; 3],
+    variance: f32,
+}
+
+// Per-pixel Gaussian mixture in the style of Stauffer & Grimson, with the
+// component update rules from Zivkovic's MOG2.
+pub struct MixtureOfGaussians {
+    learning_rate: f32,
+    detect_shadows: bool,
+    components: usize,
+    background_ratio: f32,
+    match_threshold: f32,
+    initial_variance: f32,
+    min_variance: f32,
+    pixels: Vec<Vec<Gaussian>>,
+    dimensions: (u32, u32),
+}
+
+impl MixtureOfGaussians {
+    const COMPONENTS: usize = 5;
+    const BACKGROUND_RATIO: f32 = 0.9;
+    const MATCH_THRESHOLD: f32 = 2.5;
+    const INITIAL_VARIANCE: f32 = 15.0 * 15.0;
+    const MIN_VARIANCE: f32 = 4.0 * 4.0;
+
+    pub fn new(learning_rate: f32, detect_shadows: bool) -> MixtureOfGaussians {
+        MixtureOfGaussians {
+            learning_rate,
+            detect_shadows,
+            components: MixtureOfGaussians::COMPONENTS,
+            background_ratio: MixtureOfGaussians::BACKGROUND_RATIO,
+            match_threshold: MixtureOfGaussians::MATCH_THRESHOLD,
+            initial_variance: MixtureOfGaussians::INITIAL_VARIANCE,
+            min_variance: MixtureOfGaussians::MIN_VARIANCE,
+            pixels: Vec::new(),
+            dimensions: (0, 0),
+        }
+    }
+
+    fn update_pixel(&self, mixture: &mut Vec<Gaussian>, value: [f32; 3]) -> u8 {
+        let alpha = self.learning_rate;
+        let match_threshold = self.match_threshold * self.match_threshold;
+
+        // Components are kept sorted by weight, so the background is the
+        // shortest prefix whose weights add up to `background_ratio`.
+        let mut background_count = 0;
+        let mut cumulative = 0.0;
+        for gaussian in mixture.iter() {
+            background_count += 1;
+            cumulative += gaussian.weight;
+            if cumulative > self.background_ratio {
+                break;
+            }
+        }
+
+        let mut matched = None;
+        for (i, gaussian) in mixture.iter().enumerate() {
+            let dr = value[0] - gaussian.mean[0];
+            let dg = value[1] - gaussian.mean[1];
+            let db = value[2] - gaussian.mean[2];
+            let dist = dr * dr + dg * dg + db * db;
+            if dist < match_threshold * gaussian.variance {
+                matched = Some((i, dist));
+                break;
+            }
+        }
+
+        for gaussian in mixture.iter_mut() {
+            gaussian.weight *= 1.0 - alpha;
+        }
+
+        let label = match matched {
+            Some((i, dist)) => {
+                let gaussian = &mut mixture[i];
+                gaussian.weight += alpha;
+                let rho = (alpha / gaussian.weight).min(1.0);
+                for (mean, v) in gaussian.mean.iter_mut().zip(value) {
+                    *mean += rho * (v - *mean);
+                }
+                gaussian.variance = (gaussian.variance + rho * (dist - gaussian.variance)).max(self.min_variance);
+
+                if i < background_count { BACKGROUND } else { FOREGROUND }
+            }
+            None => {
+                let replacement = Gaussian {
+                    weight: alpha,
+                    mean: value,
+                    variance: self.initial_variance,
+                };
+                if mixture.len() < self.components {
+                    mixture.push(replacement);
+                } else if let Some(last) = mixture.last_mut() {
+                    *last = replacement;
+                }
+                FOREGROUND
+            }
+        };
+
+        let total: f32 = mixture.iter().map(|g| g.weight).sum();
+        if total > 0.0 {
+            for gaussian in mixture.iter_mut() {
+                gaussian.weight /= total;
+            }
+        }
+        mixture.sort_by(|a, b| b.weight.total_cmp(&a.weight));
+
+        if label == FOREGROUND && self.detect_shadows {
+            let shadow = mixture
+                .iter()
+                .take(background_count)
+                .any(|gaussian| is_shadow(value, gaussian.mean));
+            if shadow {
+                return SHADOW;
+            }
+        }
+
+        label
+    }
+}
+
+impl BackgroundModel for MixtureOfGaussians {
+    fn apply(&mut self, frame: &RgbImage) -> Mask {
+        let (width, height) = frame.dimensions();
+        let mut mask = ImageBuffer::new(width, height);
+
+        if self.dimensions != (width, height) {
+            self.pixels = frame
+                .pixels()
+                .map(|pixel| vec![Gaussian { weight: 1.0, mean: to_f32(pixel), variance: self.initial_variance }])
+                .collect();
+            self.dimensions = (width, height);
+            return mask;
+        }
+
+        let mut pixels = std::mem::take(&mut self.pixels);
+        for (i, (x, y, pixel)) in frame.enumerate_pixels().enumerate() {
+            let label = self.update_pixel(&mut pixels[i], to_f32(pixel));
+            mask.put_pixel(x, y, Luma([label]));
+        }
+        self.pixels = pixels;
+
+        mask
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn solid(color: [u8; 3]) -> RgbImage {
+        ImageBuffer::from_pixel(8, 8, Rgb(color))
+    }
+
+    #[test]
+    pub fn test_running_average_detects_change() {
+        let mut model = RunningAverage::new(0.05, 30.0, false);
+        model.apply(&solid([100, 100, 100]));
+
+        let mut frame = solid([100, 100, 100]);
+        frame.put_pixel(3, 3, Rgb([250, 20, 20]));
+        let mask = model.apply(&frame);
+
+        assert_eq!(mask.get_pixel(3, 3).0[0], FOREGROUND);
+        assert_eq!(mask.get_pixel(0, 0).0[0], BACKGROUND);
+    }
+
+    #[test]
+    pub fn test_mog_marks_shadows() {
+        let mut model = MixtureOfGaussians::new(0.05, true);
+        for _ in 0..20 {
+            model.apply(&solid([200, 160, 120]));
+        }
+
+        let mut frame = solid([200, 160, 120]);
+        frame.put_pixel(1, 1, Rgb([140, 112, 84]));
+        frame.put_pixel(2, 2, Rgb([20, 200, 20]));
+        let mask = model.apply(&frame);
+
+        assert_eq!(mask.get_pixel(0, 0).0[0], BACKGROUND);
+        assert_eq!(mask.get_pixel(1, 1).0[0], SHADOW);
+        assert_eq!(mask.get_pixel(2, 2).0[0], FOREGROUND);
+    }
+}
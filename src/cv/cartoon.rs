@@ -0,0 +1,244 @@
+use crate::cv::{vision, helper, kmeans};
+use crate::cv::filter::{Filter, Param};
+use anyhow::{anyhow, Error};
+use image::{imageops, DynamicImage, GrayImage, ImageBuffer, Rgb, RgbImage};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Quantize {
+    Posterize,
+    Kmeans,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Edges {
+    Sobel,
+    Adaptive,
+}
+
+#[derive(Debug, Clone)]
+pub struct CartoonOptions {
+    // Bilateral filter passes, more passes give flatter colour areas.
+    pub smoothing: u32,
+    pub sigma_color: f32,
+    pub sigma_space: f32,
+    pub quantize: Quantize,
+    // Posterize levels per channel, or the number of k-means colours.
+    pub colors: usize,
+    pub edges: Edges,
+    // Adaptive threshold block size and offset.
+    pub block: u32,
+    pub c: i32,
+    // Sobel magnitude level, `None` picks it with Otsu.
+    pub edge_threshold: Option<u8>,
+    pub thickness: u32,
+}
+
+impl Default for CartoonOptions {
+    fn default() -> CartoonOptions {
+        CartoonOptions {
+            smoothing: 2,
+            sigma_color: 25.0,
+            sigma_space: 3.0,
+            quantize: Quantize::Posterize,
+            colors: 6,
+            edges: Edges::Adaptive,
+            block: 9,
+            c: 4,
+            edge_threshold: None,
+            thickness: 2,
+        }
+    }
+}
+
+impl CartoonOptions {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<CartoonOptions, Error> {
+        let mut options = CartoonOptions::default();
+
+        if let Some(smoothing) = params.get("smoothing") {
+            options.smoothing = smoothing.parse()?;
+            if options.smoothing > 10 {
+                return Err(anyhow!("smoothing must be at most 10 passes, got {}", options.smoothing));
+            }
+        }
+        if let Some(sigma_color) = params.get("sigma_color") {
+            options.sigma_color = sigma_color.parse()?;
+        }
+        if let Some(sigma_space) = params.get("sigma_space") {
+            options.sigma_space = sigma_space.parse()?;
+        }
+        if options.sigma_color <= 0.0 || options.sigma_space <= 0.0 {
+            return Err(anyhow!("sigma_color and sigma_space must be positive"));
+        }
+        // The bilateral kernel reaches 2 * sigma_space pixels in every direction.
+        if options.sigma_space > 10.0 {
+            return Err(anyhow!("sigma_space must be at most 10, got {}", options.sigma_space));
+        }
+        if let Some(quantize) = params.get("quantize") {
+            options.quantize = match quantize.as_str() {
+                "posterize" => Quantize::Posterize,
+                "kmeans" => Quantize::Kmeans,
+                other => return Err(anyhow!("Unknown quantization '{}'", other)),
+            };
+        }
+        if let Some(colors) = params.get("colors") {
+            options.colors = colors.parse()?;
+            if !(2..=64).contains(&options.colors) {
+                return Err(anyhow!("colors must be between 2 and 64"));
+            }
+        }
+        if let Some(edges) = params.get("edges") {
+            options.edges = match edges.as_str() {
+                "sobel" => Edges::Sobel,
+                "adaptive" => Edges::Adaptive,
+                other => return Err(anyhow!("Unknown edge detector '{}'", other)),
+            };
+        }
+        if let Some(block) = params.get("block") {
+            options.block = block.parse()?;
+            if options.block % 2 == 0 {
+                return Err(anyhow!("block must be odd, got {}", options.block));
+            }
+        }
+        if let Some(c) = params.get("c") {
+            options.c = c.parse()?;
+        }
+        if let Some(edge_threshold) = params.get("edge_threshold") {
+            options.edge_threshold = helper::threshold_level(edge_threshold)?;
+        }
+        if let Some(thickness) = params.get("thickness") {
+            options.thickness = thickness.parse()?;
+            if options.thickness > 16 {
+                return Err(anyhow!("thickness must be at most 16, got {}", options.thickness));
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+// Dark edge mask, 255 where an outline should be drawn.
+fn edge_mask(smoothed: &RgbImage, options: &CartoonOptions) -> Result<GrayImage, Error> {
+    let gray = vision::CompVision::to_grayscale(DynamicImage::ImageRgb8(smoothed.clone()))?;
+
+    let mut mask = match options.edges {
+        Edges::Sobel => {
+            let magnitude = vision::CompVision::edge_detection_sobel(DynamicImage::ImageLuma8(gray))?;
+            vision::CompVision::binarize(DynamicImage::ImageLuma8(magnitude), options.edge_threshold, false)?
+        }
+        Edges::Adaptive => {
+            let blurred = imageops::blur(&gray, 1.0);
+            vision::CompVision::adaptive_threshold(&blurred, options.block, options.c)
+        }
+    };
+    if options.edges == Edges::Adaptive {
+        // The adaptive threshold marks the dark side of edges black.
+        imageops::invert(&mut mask);
+    }
+
+    // Thicken the outlines with a square brush, like draw::draw_line.
+    let half = options.thickness as i64 / 2;
+    let extra = (options.thickness as i64 - 1).max(0) - half;
+    let (width, height) = mask.dimensions();
+    Ok(ImageBuffer::from_fn(width, height, |x, y| {
+        let hit = (-extra..=half).any(|dy| {
+            (-extra..=half).any(|dx| {
+                let (nx, ny) = (x as i64 + dx, y as i64 + dy);
+                nx >= 0 && ny >= 0 && nx < width as i64 && ny < height as i64 && mask.get_pixel(nx as u32, ny as u32).0[0] > 0
+            })
+        });
+        image::Luma([if hit { 255 } else { 0 }])
+    }))
+}
+
+// Edge preserving smoothing, colour quantization and thick dark outlines.
+pub fn cartoon(frame: &RgbImage, options: &CartoonOptions) -> Result<RgbImage, Error> {
+    let radius = (2.0 * options.sigma_space).ceil() as u32;
+    let mut smoothed = frame.clone();
+    for _ in 0..options.smoothing {
+        smoothed = vision::CompVision::bilateral(DynamicImage::ImageRgb8(smoothed), radius, options.sigma_color, options.sigma_space)?;
+    }
+
+    let mut test_img = match options.quantize {
+        Quantize::Posterize => vision::CompVision::posterize(DynamicImage::ImageRgb8(smoothed.clone()), options.colors)?,
+        Quantize::Kmeans => kmeans::quantize(&smoothed, options.colors),
+    };
+
+    let edges = edge_mask(&smoothed, options)?;
+    for (pixel, edge) in test_img.pixels_mut().zip(edges.pixels()) {
+        if edge.0[0] > 0 {
+            *pixel = Rgb([0, 0, 0]);
+        }
+    }
+
+    Ok(test_img)
+}
+
+impl Filter for CartoonOptions {
+    fn name() -> &'static str { "cartoon" }
+    fn label() -> &'static str { "Cartoon" }
+
+    fn params() -> Vec<Param> {
+        let defaults = CartoonOptions::default();
+        vec![
+            Param::int("smoothing", defaults.smoothing, "Bilateral filter passes"),
+            Param::float("sigma_color", defaults.sigma_color, "Colour difference still smoothed over"),
+            Param::float("sigma_space", defaults.sigma_space, "Spatial extent of the smoothing"),
+            Param::choice("quantize", &["posterize", "kmeans"], "posterize", "Colour quantization"),
+            Param::int("colors", defaults.colors, "Levels per channel or palette size"),
+            Param::choice("edges", &["adaptive", "sobel"], "adaptive", "Outline detector"),
+            Param::int("block", defaults.block, "Adaptive threshold block size, odd"),
+            Param::int("c", defaults.c, "Adaptive threshold offset"),
+            Param::text("edge_threshold", "otsu", "Sobel magnitude level, or otsu"),
+            Param::int("thickness", defaults.thickness, "Outline thickness"),
+        ]
+    }
+
+    fn from_params(params: &HashMap<String, String>) -> Result<CartoonOptions, Error> {
+        CartoonOptions::from_params(params)
+    }
+
+    fn apply(&mut self, frame: &RgbImage) -> Result<RgbImage, Error> {
+        cartoon(frame, self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_cartoon_flattens_and_outlines() {
+        // Two noisy flat halves.
+        let frame = RgbImage::from_fn(40, 20, |x, y| {
+            let noise = ((x * 7 + y * 13) % 5) as u8;
+            if x < 20 { Rgb([90 + noise, 90 + noise, 90 + noise]) } else { Rgb([200 + noise, 200 + noise, 200 + noise]) }
+        });
+
+        for quantize in [Quantize::Posterize, Quantize::Kmeans] {
+            let options = CartoonOptions { quantize, colors: 4, ..CartoonOptions::default() };
+            let out = cartoon(&frame, &options).unwrap();
+
+            assert_eq!(*out.get_pixel(19, 10), Rgb([0, 0, 0]), "{:?}", quantize);
+            for x in [3, 8, 31, 36] {
+                let reference = *out.get_pixel(x, 3);
+                assert_ne!(reference, Rgb([0, 0, 0]), "{:?}", quantize);
+                for y in 3..17 {
+                    assert_eq!(*out.get_pixel(x, y), reference, "{:?} at ({}, {})", quantize, x, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_work_per_frame_is_bounded() {
+        let error = |key: &str, value: &str| {
+            let params = [(key.to_string(), value.to_string())].into_iter().collect();
+            CartoonOptions::from_params(&params).err().unwrap().to_string()
+        };
+
+        assert!(error("smoothing", "4000000000").contains("at most 10"));
+        assert!(error("sigma_space", "1e9").contains("at most 10"));
+        assert!(error("thickness", "1000").contains("at most 16"));
+    }
+}
@@ -0,0 +1,267 @@
+use crate::cv::{vision, helper, draw, hough, stream};
+use crate::cv::filter::{Filter, Param};
+use anyhow::{anyhow, Error};
+use image::{DynamicImage, GrayImage, Rgb, RgbImage};
+use serde::Serialize;
+use std::collections::HashMap;
+use indicatif::{ProgressBar, ProgressStyle};
+
+#[derive(Debug, Clone)]
+pub struct CircleOptions {
+    pub min_radius: u32,
+    // 0 uses half of the smaller image side.
+    pub max_radius: u32,
+    // Minimum number of gradient votes a centre needs.
+    pub threshold: u32,
+    pub min_distance: f32,
+    // Level the Sobel magnitude is binarized at, `None` picks it with Otsu.
+    pub edge_threshold: Option<u8>,
+    // 0 keeps every circle.
+    pub max_circles: usize,
+}
+
+impl Default for CircleOptions {
+    fn default() -> CircleOptions {
+        CircleOptions {
+            min_radius: 5,
+            max_radius: 0,
+            threshold: 30,
+            min_distance: 20.0,
+            edge_threshold: None,
+            max_circles: 20,
+        }
+    }
+}
+
+impl CircleOptions {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<CircleOptions, Error> {
+        let mut options = CircleOptions::default();
+
+        if let Some(min_radius) = params.get("min_radius") {
+            options.min_radius = min_radius.parse()?;
+        }
+        if let Some(max_radius) = params.get("max_radius") {
+            options.max_radius = max_radius.parse()?;
+            if options.max_radius > helper::MAX_SIDE {
+                return Err(anyhow!("max_radius must be at most {}", helper::MAX_SIDE));
+            }
+        }
+        if options.max_radius != 0 && options.max_radius < options.min_radius {
+            return Err(anyhow!("max_radius must not be smaller than min_radius"));
+        }
+        if let Some(threshold) = params.get("threshold") {
+            options.threshold = threshold.parse()?;
+        }
+        if let Some(min_distance) = params.get("min_distance") {
+            options.min_distance = min_distance.parse()?;
+        }
+        if let Some(edge_threshold) = params.get("edge_threshold") {
+            options.edge_threshold = helper::threshold_level(edge_threshold)?;
+        }
+        if let Some(max_circles) = params.get("max_circles") {
+            options.max_circles = max_circles.parse()?;
+        }
+
+        Ok(options)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Circle {
+    pub x: u32,
+    pub y: u32,
+    pub radius: u32,
+    pub votes: u32,
+}
+
+// Gradient Hough transform (the 2-1 Hough of Yuen et al.): every edge pixel
+// votes for the centres along its gradient direction, both ways, for all
+// radii in range. Centres are the accumulator peaks, and each radius is then
+// the distance most edge pixels share from that centre.
+pub fn detect_circles(gray: &GrayImage, options: &CircleOptions) -> Result<Vec<Circle>, Error> {
+    let (width, height) = gray.dimensions();
+    // No circle with its centre in the image is wider than half the
+    // diagonal, larger radii would only cost time and memory.
+    let half_diagonal = ((width as f32).hypot(height as f32) / 2.0).ceil() as u32;
+    let max_radius = match options.max_radius {
+        0 => width.min(height) / 2,
+        radius => radius.min(half_diagonal),
+    };
+    let min_radius = options.min_radius.min(max_radius);
+
+    let edges = hough::edge_map(gray, options.edge_threshold)?;
+    let gradients = vision::CompVision::sobel_gradients(gray);
+
+    let mut points = Vec::new();
+    let mut accumulator = vec![0u32; (width * height) as usize];
+    for (x, y, pixel) in edges.enumerate_pixels() {
+        let index = (y * width + x) as usize;
+        let magnitude = gradients.magnitude(index);
+        if pixel.0[0] == 0 || magnitude == 0.0 {
+            continue;
+        }
+        points.push((x as f32, y as f32));
+
+        let (dx, dy) = (gradients.gx[index] / magnitude, gradients.gy[index] / magnitude);
+        for sign in [-1.0, 1.0] {
+            for r in min_radius..=max_radius {
+                let cx = (x as f32 + sign * dx * r as f32).round();
+                let cy = (y as f32 + sign * dy * r as f32).round();
+                if cx < 0.0 || cy < 0.0 || cx >= width as f32 || cy >= height as f32 {
+                    break;
+                }
+                accumulator[(cy as u32 * width + cx as u32) as usize] += 1;
+            }
+        }
+    }
+
+    let at = |x: u32, y: u32| accumulator[(y * width + x) as usize];
+    let mut centres = Vec::new();
+    for y in 1..height.saturating_sub(1) {
+        for x in 1..width.saturating_sub(1) {
+            let votes = at(x, y);
+            if votes < options.threshold.max(1) {
+                continue;
+            }
+            let is_peak = (y - 1..=y + 1)
+                .flat_map(|ny| (x - 1..=x + 1).map(move |nx| (nx, ny)))
+                .all(|(nx, ny)| at(nx, ny) <= votes);
+            if is_peak {
+                centres.push((x, y, votes));
+            }
+        }
+    }
+    centres.sort_by_key(|&(_, _, votes)| std::cmp::Reverse(votes));
+
+    let min_distance = options.min_distance * options.min_distance;
+    let mut circles: Vec<Circle> = Vec::new();
+    for (x, y, votes) in centres {
+        if options.max_circles > 0 && circles.len() >= options.max_circles {
+            break;
+        }
+        let too_close = circles.iter().any(|circle| {
+            let dx = circle.x as f32 - x as f32;
+            let dy = circle.y as f32 - y as f32;
+            dx * dx + dy * dy < min_distance
+        });
+        if too_close {
+            continue;
+        }
+
+        // Distance histogram of the edge pixels, smoothed over 3 bins.
+        let mut histogram = vec![0u32; max_radius as usize + 2];
+        for &(px, py) in &points {
+            let distance = ((px - x as f32).powi(2) + (py - y as f32).powi(2)).sqrt().round() as u32;
+            if (min_radius..=max_radius).contains(&distance) {
+                histogram[distance as usize] += 1;
+            }
+        }
+        let radius = (min_radius..=max_radius)
+            .max_by_key(|&r| {
+                let r = r as usize;
+                histogram[r.saturating_sub(1)] + histogram[r] + histogram[r + 1]
+            })
+            .unwrap_or(min_radius);
+
+        circles.push(Circle { x, y, radius, votes });
+    }
+
+    Ok(circles)
+}
+
+pub fn draw_circles(frame: &RgbImage, options: &CircleOptions) -> Result<RgbImage, Error> {
+    let gray = vision::CompVision::to_grayscale(DynamicImage::ImageRgb8(frame.clone()))?;
+
+    let mut test_img = frame.clone();
+    for circle in detect_circles(&gray, options)? {
+        let center = (circle.x as f32, circle.y as f32);
+        draw::draw_circle(&mut test_img, center, circle.radius as f32, Rgb([0, 255, 0]));
+        draw::draw_circle(&mut test_img, center, circle.radius as f32 + 1.0, Rgb([0, 255, 0]));
+        draw::draw_circle(&mut test_img, center, 1.0, Rgb([255, 0, 0]));
+    }
+
+    Ok(test_img)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FrameCircles {
+    pub frame: usize,
+    pub time: f32,
+    pub circles: Vec<Circle>,
+}
+
+// Circles found in every extracted frame.
+pub fn circle_stats(video_path: &str, options: &CircleOptions, workers: usize) -> Result<Vec<FrameCircles>, Error>{
+    let video = helper::VideoOptions::analysis();
+    let reader = stream::FrameReader::open(video_path, &video)?;
+    let fps = reader.fps();
+
+    let pb = ProgressBar::new(reader.expected_frames);
+    pb.set_style(ProgressStyle::default_bar()
+        .template("{msg} {bar:40.cyan/blue} {percent:>3}%")?
+        .progress_chars("█▌▐"));
+
+    // Frames are independent, map_frames hands them back in order.
+    let frames = stream::map_frames(Box::new(reader), workers, |i, frame| {
+        let gray = vision::CompVision::to_grayscale(DynamicImage::ImageRgb8(frame.clone()))?;
+        let circles = detect_circles(&gray, options)?;
+        pb.inc(1);
+        Ok(FrameCircles { frame: i, time: i as f32 / fps, circles })
+    })?;
+
+    Ok(frames)
+}
+
+impl Filter for CircleOptions {
+    fn name() -> &'static str { "circles" }
+    fn label() -> &'static str { "Hough Circles" }
+
+    fn params() -> Vec<Param> {
+        let defaults = CircleOptions::default();
+        vec![
+            Param::int("min_radius", defaults.min_radius, "Smallest radius searched"),
+            Param::int("max_radius", defaults.max_radius, "Largest radius searched, 0 for half the frame"),
+            Param::int("threshold", defaults.threshold, "Votes needed for a centre"),
+            Param::float("min_distance", defaults.min_distance, "Minimum distance between centres"),
+            Param::text("edge_threshold", "otsu", "Sobel magnitude level, or otsu"),
+            Param::int("max_circles", defaults.max_circles, "Most circles per frame, 0 for all"),
+        ]
+    }
+
+    fn from_params(params: &HashMap<String, String>) -> Result<CircleOptions, Error> {
+        CircleOptions::from_params(params)
+    }
+
+    fn apply(&mut self, frame: &RgbImage) -> Result<RgbImage, Error> {
+        draw_circles(frame, self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_finds_two_discs() {
+        let discs = [(40.0, 40.0, 15.0), (100.0, 50.0, 10.0)];
+        let gray = GrayImage::from_fn(140, 90, |x, y| {
+            let inside = discs
+                .iter()
+                .any(|&(cx, cy, r)| (x as f32 - cx).powi(2) + (y as f32 - cy).powi(2) <= r * r);
+            image::Luma([if inside { 220 } else { 30 }])
+        });
+
+        let options = CircleOptions { max_radius: 30, ..CircleOptions::default() };
+        let circles = detect_circles(&gray, &options).unwrap();
+
+        assert_eq!(circles.len(), 2, "{:?}", circles);
+        let huge = CircleOptions { max_radius: u32::MAX, ..CircleOptions::default() };
+        assert_eq!(detect_circles(&gray, &huge).unwrap().len(), 2);
+        for (cx, cy, r) in discs {
+            let found = circles.iter().any(|c| {
+                (c.x as f32 - cx).abs() <= 2.0 && (c.y as f32 - cy).abs() <= 2.0 && (c.radius as f32 - r).abs() <= 2.0
+            });
+            assert!(found, "no circle near ({}, {}) r={}: {:?}", cx, cy, r, circles);
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use crate::cv::{vision, helper, stream};
+use crate::cv::background::{self, BackgroundModel, MixtureOfGaussians};
+use anyhow::{anyhow, Error};
+use image::DynamicImage;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+use indicatif::{ProgressBar, ProgressStyle};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scoring {
+    FrameDifference,
+    Background,
+}
+
+#[derive(Debug, Clone)]
+pub struct ClipOptions {
+    pub scoring: Scoring,
+    pub threshold: f32,
+    pub pre_roll: f32,
+    pub post_roll: f32,
+}
+
+impl Default for ClipOptions {
+    fn default() -> ClipOptions {
+        ClipOptions {
+            scoring: Scoring::FrameDifference,
+            threshold: 0.02,
+            pre_roll: 1.0,
+            post_roll: 1.0,
+        }
+    }
+}
+
+impl ClipOptions {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<ClipOptions, Error> {
+        let mut options = ClipOptions::default();
+
+        if let Some(scoring) = params.get("scoring") {
+            options.scoring = match scoring.as_str() {
+                "difference" => Scoring::FrameDifference,
+                "background" => Scoring::Background,
+                other => return Err(anyhow!("Unknown motion scoring '{}'", other)),
+            };
+        }
+        if let Some(threshold) = params.get("threshold") {
+            options.threshold = threshold.parse()?;
+        }
+        if let Some(pre_roll) = params.get("pre_roll") {
+            options.pre_roll = pre_roll.parse()?;
+        }
+        if let Some(post_roll) = params.get("post_roll") {
+            options.post_roll = post_roll.parse()?;
+        }
+        if options.pre_roll < 0.0 || options.post_roll < 0.0 {
+            return Err(anyhow!("pre_roll and post_roll must not be negative"));
+        }
+
+        Ok(options)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Segment {
+    pub start: f32,
+    pub end: f32,
+    pub peak_score: f32,
+}
+
+// Grey levels two consecutive frames have to differ by before a pixel counts as moving.
+const DIFFERENCE_THRESHOLD: i16 = 25;
+
+// Scores every extracted frame with the fraction of its pixels that moved.
+pub fn score_motion(video_path: &str, scoring: Scoring) -> Result<Vec<f32>, Error>{
+    let reader = stream::FrameReader::open(video_path, &helper::VideoOptions::analysis())?;
+
+    let pb = ProgressBar::new(reader.expected_frames);
+    pb.set_style(ProgressStyle::default_bar()
+        .template("{msg} {bar:40.cyan/blue} {percent:>3}%")?
+        .progress_chars("█▌▐"));
+
+    let mut scores = Vec::with_capacity(reader.expected_frames as usize);
+    let mut previous: Option<image::GrayImage> = None;
+    let mut model = MixtureOfGaussians::new(0.01, false);
+
+    for frame in reader{
+        let frame = frame?;
+
+        let score = match scoring {
+            Scoring::FrameDifference => {
+                let gray = vision::CompVision::to_grayscale(DynamicImage::ImageRgb8(frame))?;
+                let score = match &previous {
+                    Some(previous) => {
+                        let moving = gray
+                            .pixels()
+                            .zip(previous.pixels())
+                            .filter(|(a, b)| (a.0[0] as i16 - b.0[0] as i16).abs() > DIFFERENCE_THRESHOLD)
+                            .count();
+                        moving as f32 / gray.len() as f32
+                    }
+                    None => 0.0,
+                };
+                previous = Some(gray);
+                score
+            }
+            Scoring::Background => {
+                let mask = model.apply(&frame);
+                let moving = mask.pixels().filter(|p| p.0[0] == background::FOREGROUND).count();
+                moving as f32 / mask.len() as f32
+            }
+        };
+
+        scores.push(score);
+        pb.inc(1);
+    }
+
+    Ok(scores)
+}
+
+// Groups frames scoring above `threshold` into padded segments, merging any
+// that overlap once the pre-roll and post-roll are applied.
+pub fn find_segments(scores: &[f32], fps: f32, options: &ClipOptions) -> Vec<Segment> {
+    let duration = scores.len() as f32 / fps;
+    let mut segments: Vec<Segment> = Vec::new();
+    let mut i = 0;
+
+    while i < scores.len() {
+        if scores[i] <= options.threshold {
+            i += 1;
+            continue;
+        }
+
+        let first = i;
+        let mut peak_score = scores[i];
+        while i < scores.len() && scores[i] > options.threshold {
+            peak_score = peak_score.max(scores[i]);
+            i += 1;
+        }
+
+        let start = (first as f32 / fps - options.pre_roll).max(0.0);
+        let end = (i as f32 / fps + options.post_roll).min(duration);
+
+        match segments.last_mut() {
+            Some(last) if start <= last.end => {
+                last.end = last.end.max(end);
+                last.peak_score = last.peak_score.max(peak_score);
+            }
+            _ => segments.push(Segment { start, end, peak_score }),
+        }
+    }
+
+    segments
+}
+
+// Cuts every segment out of the source video into `dir`, returns the clip
+// paths in order.
+pub fn extract_clips(video_path: &str, segments: &[Segment], dir: &Path) -> Result<Vec<String>, Error>{
+    let mut clips = Vec::with_capacity(segments.len());
+
+    for (i, segment) in segments.iter().enumerate() {
+        let clip_path = dir.join(format!("motion_clip_{:03}.mp4", i + 1)).to_string_lossy().to_string();
+        helper::cut_clip(video_path, segment.start, segment.end, &clip_path)?;
+        clips.push(clip_path);
+    }
+
+    Ok(clips)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_find_segments_pads_and_merges() {
+        let options = ClipOptions { threshold: 0.5, pre_roll: 0.2, post_roll: 0.2, ..ClipOptions::default() };
+        let scores = [0.0, 0.9, 0.0, 0.0, 0.8, 0.0, 0.0, 0.0, 0.0, 0.0, 0.7, 0.0];
+
+        let segments = find_segments(&scores, 10.0, &options);
+
+        assert_eq!(segments.len(), 2);
+        assert!((segments[0].start - 0.0).abs() < 1e-6);
+        assert!((segments[0].end - 0.7).abs() < 1e-6);
+        assert!((segments[0].peak_score - 0.9).abs() < 1e-6);
+        assert!((segments[1].start - 0.8).abs() < 1e-6);
+        assert!((segments[1].end - 1.2).abs() < 1e-6);
+    }
+}
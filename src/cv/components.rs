@@ -0,0 +1,380 @@
+use crate::cv::{vision, helper, stream};
+use crate::cv::filter::{Filter, Param};
+use anyhow::{anyhow, Error};
+use image::{DynamicImage, GrayImage, ImageBuffer, Rgb, RgbImage};
+use serde::Serialize;
+use std::collections::HashMap;
+use indicatif::{ProgressBar, ProgressStyle};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Connectivity {
+    Four,
+    Eight,
+}
+
+// Label image, 0 is background and regions are numbered from 1 in raster order.
+#[derive(Debug, Clone)]
+pub struct Labels {
+    pub width: u32,
+    pub height: u32,
+    pub labels: Vec<u32>,
+    pub count: u32,
+}
+
+impl Labels {
+    pub fn get(&self, x: u32, y: u32) -> u32 {
+        self.labels[(y * self.width + x) as usize]
+    }
+}
+
+fn find(parents: &mut [u32], mut label: u32) -> u32 {
+    while parents[label as usize] != label {
+        parents[label as usize] = parents[parents[label as usize] as usize];
+        label = parents[label as usize];
+    }
+    label
+}
+
+fn union(parents: &mut [u32], a: u32, b: u32) -> u32 {
+    let (a, b) = (find(parents, a), find(parents, b));
+    let (low, high) = if a < b { (a, b) } else { (b, a) };
+    parents[high as usize] = low;
+    low
+}
+
+// Classic two pass labelling with union-find over provisional labels.
+pub fn label_components(binary: &GrayImage, connectivity: Connectivity) -> Labels {
+    let (width, height) = binary.dimensions();
+    let mut labels = vec![0u32; (width * height) as usize];
+    let mut parents = vec![0u32];
+
+    // Neighbours that have already been visited in raster order.
+    let neighbours: &[(i64, i64)] = match connectivity {
+        Connectivity::Four => &[(-1, 0), (0, -1)],
+        Connectivity::Eight => &[(-1, 0), (-1, -1), (0, -1), (1, -1)],
+    };
+
+    for y in 0..height {
+        for x in 0..width {
+            if binary.get_pixel(x, y).0[0] == 0 {
+                continue;
+            }
+
+            let mut label = 0;
+            for &(dx, dy) in neighbours {
+                let (nx, ny) = (x as i64 + dx, y as i64 + dy);
+                if nx < 0 || ny < 0 || nx >= width as i64 {
+                    continue;
+                }
+                let neighbour = labels[(ny as u32 * width + nx as u32) as usize];
+                if neighbour == 0 {
+                    continue;
+                }
+                label = if label == 0 { neighbour } else { union(&mut parents, label, neighbour) };
+            }
+
+            if label == 0 {
+                label = parents.len() as u32;
+                parents.push(label);
+            }
+            labels[(y * width + x) as usize] = label;
+        }
+    }
+
+    // Second pass: resolve equivalences and renumber consecutively.
+    let mut final_labels = vec![0u32; parents.len()];
+    let mut count = 0;
+    for label in 1..parents.len() as u32 {
+        let root = find(&mut parents, label);
+        if root == label {
+            count += 1;
+            final_labels[label as usize] = count;
+        }
+    }
+    for label in labels.iter_mut().filter(|label| **label != 0) {
+        *label = final_labels[find(&mut parents, *label) as usize];
+    }
+
+    Labels { width, height, labels, count }
+}
+
+// Spatial, central and normalised central moments, named like OpenCV's.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Moments {
+    pub m00: f64, pub m10: f64, pub m01: f64,
+    pub m20: f64, pub m11: f64, pub m02: f64,
+    pub m30: f64, pub m21: f64, pub m12: f64, pub m03: f64,
+    pub mu20: f64, pub mu11: f64, pub mu02: f64,
+    pub mu30: f64, pub mu21: f64, pub mu12: f64, pub mu03: f64,
+    pub nu20: f64, pub nu11: f64, pub nu02: f64,
+    pub nu30: f64, pub nu21: f64, pub nu12: f64, pub nu03: f64,
+}
+
+impl Moments {
+    fn add(&mut self, x: f64, y: f64) {
+        self.m00 += 1.0;
+        self.m10 += x;
+        self.m01 += y;
+        self.m20 += x * x;
+        self.m11 += x * y;
+        self.m02 += y * y;
+        self.m30 += x * x * x;
+        self.m21 += x * x * y;
+        self.m12 += x * y * y;
+        self.m03 += y * y * y;
+    }
+
+    // Fills in the central and normalised moments from the spatial ones.
+    fn complete(&mut self) {
+        if self.m00 == 0.0 {
+            return;
+        }
+        let (cx, cy) = (self.m10 / self.m00, self.m01 / self.m00);
+
+        self.mu20 = self.m20 - cx * self.m10;
+        self.mu11 = self.m11 - cx * self.m01;
+        self.mu02 = self.m02 - cy * self.m01;
+        self.mu30 = self.m30 - 3.0 * cx * self.m20 + 2.0 * cx * cx * self.m10;
+        self.mu21 = self.m21 - 2.0 * cx * self.m11 - cy * self.m20 + 2.0 * cx * cx * self.m01;
+        self.mu12 = self.m12 - 2.0 * cy * self.m11 - cx * self.m02 + 2.0 * cy * cy * self.m10;
+        self.mu03 = self.m03 - 3.0 * cy * self.m02 + 2.0 * cy * cy * self.m01;
+
+        let second = self.m00 * self.m00;
+        let third = second * self.m00.sqrt();
+        self.nu20 = self.mu20 / second;
+        self.nu11 = self.mu11 / second;
+        self.nu02 = self.mu02 / second;
+        self.nu30 = self.mu30 / third;
+        self.nu21 = self.mu21 / third;
+        self.nu12 = self.mu12 / third;
+        self.nu03 = self.mu03 / third;
+    }
+
+    // The seven moment invariants from Hu (1962).
+    pub fn hu(&self) -> [f64; 7] {
+        let (n20, n11, n02) = (self.nu20, self.nu11, self.nu02);
+        let (n30, n21, n12, n03) = (self.nu30, self.nu21, self.nu12, self.nu03);
+
+        let a = n30 + n12;
+        let b = n21 + n03;
+        let c = n30 - 3.0 * n12;
+        let d = 3.0 * n21 - n03;
+
+        [
+            n20 + n02,
+            (n20 - n02).powi(2) + 4.0 * n11 * n11,
+            c * c + d * d,
+            a * a + b * b,
+            c * a * (a * a - 3.0 * b * b) + d * b * (3.0 * a * a - b * b),
+            (n20 - n02) * (a * a - b * b) + 4.0 * n11 * a * b,
+            d * a * (a * a - 3.0 * b * b) - c * b * (3.0 * a * a - b * b),
+        ]
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Region {
+    pub label: u32,
+    pub area: u32,
+    // x, y, width, height
+    pub bounding_box: (u32, u32, u32, u32),
+    pub centroid: (f64, f64),
+    pub moments: Moments,
+    pub hu: [f64; 7],
+}
+
+pub fn region_properties(labels: &Labels) -> Vec<Region> {
+    let count = labels.count as usize;
+    let mut moments = vec![Moments::default(); count];
+    let mut bounds = vec![(u32::MAX, u32::MAX, 0u32, 0u32); count];
+
+    for y in 0..labels.height {
+        for x in 0..labels.width {
+            let label = labels.get(x, y);
+            if label == 0 {
+                continue;
+            }
+            let i = label as usize - 1;
+            moments[i].add(x as f64, y as f64);
+            let b = &mut bounds[i];
+            *b = (b.0.min(x), b.1.min(y), b.2.max(x), b.3.max(y));
+        }
+    }
+
+    moments
+        .into_iter()
+        .zip(bounds)
+        .enumerate()
+        .map(|(i, (mut moments, (min_x, min_y, max_x, max_y)))| {
+            moments.complete();
+            Region {
+                label: i as u32 + 1,
+                area: moments.m00 as u32,
+                bounding_box: (min_x, min_y, max_x - min_x + 1, max_y - min_y + 1),
+                centroid: (moments.m10 / moments.m00, moments.m01 / moments.m00),
+                hu: moments.hu(),
+                moments,
+            }
+        })
+        .collect()
+}
+
+// Spreads labels around the hue circle with the golden angle so that
+// neighbouring labels get clearly different colours.
+fn label_color(label: u32) -> Rgb<u8> {
+    let hue = (label as f32 * 137.507_77) % 360.0;
+    let sector = hue / 60.0;
+    let x = 1.0 - (sector % 2.0 - 1.0).abs();
+    let (r, g, b) = match sector as u32 {
+        0 => (1.0, x, 0.0),
+        1 => (x, 1.0, 0.0),
+        2 => (0.0, 1.0, x),
+        3 => (0.0, x, 1.0),
+        4 => (x, 0.0, 1.0),
+        _ => (1.0, 0.0, x),
+    };
+    Rgb([(r * 255.0) as u8, (g * 255.0) as u8, (b * 255.0) as u8])
+}
+
+pub fn colorize(labels: &Labels) -> RgbImage {
+    ImageBuffer::from_fn(labels.width, labels.height, |x, y| match labels.get(x, y) {
+        0 => Rgb([0, 0, 0]),
+        label => label_color(label),
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct ComponentOptions {
+    pub threshold: Option<u8>,
+    pub invert: bool,
+    pub connectivity: Connectivity,
+    // Regions smaller than this are dropped from the statistics.
+    pub min_area: u32,
+}
+
+impl Default for ComponentOptions {
+    fn default() -> ComponentOptions {
+        ComponentOptions {
+            threshold: None,
+            invert: false,
+            connectivity: Connectivity::Eight,
+            min_area: 1,
+        }
+    }
+}
+
+impl ComponentOptions {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<ComponentOptions, Error> {
+        let mut options = ComponentOptions::default();
+
+        if let Some(threshold) = params.get("threshold") {
+            options.threshold = helper::threshold_level(threshold)?;
+        }
+        if let Some(invert) = params.get("invert") {
+            options.invert = invert.parse()?;
+        }
+        if let Some(connectivity) = params.get("connectivity") {
+            options.connectivity = match connectivity.as_str() {
+                "4" => Connectivity::Four,
+                "8" => Connectivity::Eight,
+                other => return Err(anyhow!("Connectivity must be 4 or 8, got '{}'", other)),
+            };
+        }
+        if let Some(min_area) = params.get("min_area") {
+            options.min_area = min_area.parse()?;
+        }
+
+        Ok(options)
+    }
+
+    pub fn label(&self, frame: DynamicImage) -> Result<Labels, Error> {
+        let binary = vision::CompVision::binarize(frame, self.threshold, self.invert)?;
+        Ok(label_components(&binary, self.connectivity))
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FrameRegions {
+    pub frame: usize,
+    pub time: f32,
+    pub regions: Vec<Region>,
+}
+
+// Region statistics of every extracted frame.
+pub fn component_stats(video_path: &str, options: &ComponentOptions, workers: usize) -> Result<Vec<FrameRegions>, Error>{
+    let video = helper::VideoOptions::analysis();
+    let reader = stream::FrameReader::open(video_path, &video)?;
+    let fps = reader.fps();
+
+    let pb = ProgressBar::new(reader.expected_frames);
+    pb.set_style(ProgressStyle::default_bar()
+        .template("{msg} {bar:40.cyan/blue} {percent:>3}%")?
+        .progress_chars("█▌▐"));
+
+    // Frames are independent, map_frames hands them back in order.
+    let frames = stream::map_frames(Box::new(reader), workers, |i, frame| {
+        let labels = options.label(DynamicImage::ImageRgb8(frame.clone()))?;
+        let regions = region_properties(&labels)
+            .into_iter()
+            .filter(|region| region.area >= options.min_area)
+            .collect();
+        pb.inc(1);
+        Ok(FrameRegions { frame: i, time: i as f32 / fps, regions })
+    })?;
+
+    Ok(frames)
+}
+
+// Colours every region of the binarised frame.
+pub fn draw_components(frame: &RgbImage, options: &ComponentOptions) -> Result<RgbImage, Error> {
+    Ok(colorize(&options.label(DynamicImage::ImageRgb8(frame.clone()))?))
+}
+
+impl Filter for ComponentOptions {
+    fn name() -> &'static str { "components" }
+    fn label() -> &'static str { "Components" }
+
+    fn params() -> Vec<Param> {
+        let defaults = ComponentOptions::default();
+        vec![
+            Param::text("threshold", "otsu", "Binarisation level, or otsu"),
+            Param::bool("invert", defaults.invert, "Label dark objects on a light background"),
+            Param::choice("connectivity", &["4", "8"], "8", "Pixel neighbourhood"),
+            Param::int("min_area", defaults.min_area, "Smallest region reported"),
+        ]
+    }
+
+    fn from_params(params: &HashMap<String, String>) -> Result<ComponentOptions, Error> {
+        ComponentOptions::from_params(params)
+    }
+
+    fn apply(&mut self, frame: &RgbImage) -> Result<RgbImage, Error> {
+        draw_components(frame, self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_labels_and_region_properties() {
+        // A 10x10 square and a single pixel touching its corner diagonally.
+        let binary = GrayImage::from_fn(16, 16, |x, y| {
+            let square = (1..11).contains(&x) && (1..11).contains(&y);
+            image::Luma([if square || (x == 11 && y == 11) { 255 } else { 0 }])
+        });
+
+        assert_eq!(label_components(&binary, Connectivity::Four).count, 2);
+        assert_eq!(label_components(&binary, Connectivity::Eight).count, 1);
+
+        let regions = region_properties(&label_components(&binary, Connectivity::Four));
+        let square = &regions[0];
+        assert_eq!(square.area, 100);
+        assert_eq!(square.bounding_box, (1, 1, 10, 10));
+        assert!((square.centroid.0 - 5.5).abs() < 1e-9 && (square.centroid.1 - 5.5).abs() < 1e-9);
+        // nu20 = nu02 = (n^2 - 1) / (12 n^2) for an n by n square.
+        assert!((square.hu[0] - 2.0 * 99.0 / 1200.0).abs() < 1e-9);
+        assert!(square.hu[1].abs() < 1e-12);
+    }
+}
@@ -0,0 +1,498 @@
+use crate::cv::{vision, helper, draw};
+use crate::cv::filter::{Filter, Param};
+use anyhow::{anyhow, Error};
+use image::{DynamicImage, GrayImage, Rgb, RgbImage};
+use serde::Serialize;
+use std::collections::HashMap;
+
+pub type Point = (i32, i32);
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Contour {
+    pub points: Vec<Point>,
+    pub is_hole: bool,
+    // Index of the enclosing contour: holes point at the outer border they
+    // are cut out of, and outer borders at the hole they sit in.
+    pub parent: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct RotatedRect {
+    pub center: (f32, f32),
+    pub size: (f32, f32),
+    pub angle: f32,
+}
+
+impl RotatedRect {
+    pub fn corners(&self) -> [(f32, f32); 4] {
+        let (cos, sin) = (self.angle.to_radians().cos(), self.angle.to_radians().sin());
+        let (hw, hh) = (self.size.0 / 2.0, self.size.1 / 2.0);
+        let corner = |u: f32, v: f32| (self.center.0 + u * cos - v * sin, self.center.1 + u * sin + v * cos);
+        [corner(-hw, -hh), corner(hw, -hh), corner(hw, hh), corner(-hw, hh)]
+    }
+}
+
+// Neighbours in clockwise order (on screen, y pointing down) starting east.
+const DIRECTIONS: [Point; 8] = [(1, 0), (1, 1), (0, 1), (-1, 1), (-1, 0), (-1, -1), (0, -1), (1, -1)];
+
+fn direction(from: Point, to: Point) -> usize {
+    let delta = (to.0 - from.0, to.1 - from.1);
+    DIRECTIONS.iter().position(|&d| d == delta).unwrap_or(0)
+}
+
+// Label grid with a one pixel zero frame around the image, as the border
+// following algorithm expects.
+struct Grid {
+    labels: Vec<i32>,
+    width: i32,
+}
+
+impl Grid {
+    fn get(&self, p: Point) -> i32 {
+        self.labels[(p.1 * self.width + p.0) as usize]
+    }
+
+    fn set(&mut self, p: Point, value: i32) {
+        self.labels[(p.1 * self.width + p.0) as usize] = value;
+    }
+}
+
+// Follows one border starting at `start`, entering from the zero pixel `from`
+// (steps 3.1 to 3.5 of Suzuki & Abe).
+fn follow_border(grid: &mut Grid, start: Point, from: Point, nbd: i32) -> Vec<Point> {
+    let offset = |p: Point, d: usize| (p.0 + DIRECTIONS[d].0, p.1 + DIRECTIONS[d].1);
+
+    let first_direction = direction(start, from);
+    let first = (0..8)
+        .map(|k| offset(start, (first_direction + k) % 8))
+        .find(|&p| grid.get(p) != 0);
+
+    let Some(first) = first else {
+        // Isolated pixel.
+        grid.set(start, -nbd);
+        return vec![start];
+    };
+
+    let mut points = Vec::new();
+    let (mut previous, mut current) = (first, start);
+
+    loop {
+        let back = direction(current, previous);
+        let mut east_is_zero = false;
+        let mut next = previous;
+        for k in 1..=8 {
+            let d = (back + 8 - k) % 8;
+            let candidate = offset(current, d);
+            if grid.get(candidate) != 0 {
+                next = candidate;
+                break;
+            }
+            if d == 0 {
+                east_is_zero = true;
+            }
+        }
+
+        if east_is_zero {
+            grid.set(current, -nbd);
+        } else if grid.get(current) == 1 {
+            grid.set(current, nbd);
+        }
+        points.push(current);
+
+        if next == start && current == first {
+            break;
+        }
+        previous = current;
+        current = next;
+    }
+
+    points
+}
+
+// Border following from Suzuki & Abe (1985). Every non-zero pixel counts as
+// foreground. Contours are returned in raster order of their starting pixel.
+pub fn find_contours(binary: &GrayImage) -> Vec<Contour> {
+    let (width, height) = (binary.width() as i32 + 2, binary.height() as i32 + 2);
+    let mut grid = Grid { labels: vec![0; (width * height) as usize], width };
+    for (x, y, pixel) in binary.enumerate_pixels() {
+        if pixel.0[0] != 0 {
+            grid.set((x as i32 + 1, y as i32 + 1), 1);
+        }
+    }
+
+    // Border number 1 is the image frame, which behaves like a hole.
+    let mut borders: Vec<(bool, Option<i32>)> = vec![(true, None), (true, None)];
+    let mut contours = Vec::new();
+    let mut nbd = 1;
+
+    for y in 1..height - 1 {
+        let mut lnbd = 1;
+        for x in 1..width - 1 {
+            let value = grid.get((x, y));
+
+            let start = if value == 1 && grid.get((x - 1, y)) == 0 {
+                Some((false, (x - 1, y)))
+            } else if value >= 1 && grid.get((x + 1, y)) == 0 {
+                if value > 1 {
+                    lnbd = value;
+                }
+                Some((true, (x + 1, y)))
+            } else {
+                None
+            };
+
+            if let Some((is_hole, from)) = start {
+                nbd += 1;
+
+                let (lnbd_is_hole, lnbd_parent) = borders[lnbd as usize];
+                let parent = if is_hole == lnbd_is_hole { lnbd_parent } else { Some(lnbd) };
+                borders.push((is_hole, parent));
+
+                let points = follow_border(&mut grid, (x, y), from, nbd)
+                    .into_iter()
+                    .map(|(px, py)| (px - 1, py - 1))
+                    .collect();
+
+                contours.push(Contour {
+                    points,
+                    is_hole,
+                    parent: parent.filter(|&p| p > 1).map(|p| (p - 2) as usize),
+                });
+            }
+
+            let value = grid.get((x, y));
+            if value.abs() > 1 {
+                lnbd = value.abs();
+            }
+        }
+    }
+
+    contours
+}
+
+fn to_f32(point: Point) -> (f32, f32) {
+    (point.0 as f32, point.1 as f32)
+}
+
+// Shoelace formula, always positive.
+pub fn contour_area(points: &[Point]) -> f32 {
+    let mut sum = 0i64;
+    for (i, &(x0, y0)) in points.iter().enumerate() {
+        let (x1, y1) = points[(i + 1) % points.len()];
+        sum += x0 as i64 * y1 as i64 - x1 as i64 * y0 as i64;
+    }
+    (sum.abs() as f32) / 2.0
+}
+
+// Length of the closed polygon through `points`.
+pub fn contour_perimeter(points: &[Point]) -> f32 {
+    points
+        .iter()
+        .enumerate()
+        .map(|(i, &p)| {
+            let q = points[(i + 1) % points.len()];
+            (((q.0 - p.0).pow(2) + (q.1 - p.1).pow(2)) as f32).sqrt()
+        })
+        .sum()
+}
+
+fn point_line_distance(p: Point, a: Point, b: Point) -> f32 {
+    let (px, py) = to_f32(p);
+    let (ax, ay) = to_f32(a);
+    let (bx, by) = to_f32(b);
+    let length = ((bx - ax).powi(2) + (by - ay).powi(2)).sqrt();
+    if length == 0.0 {
+        return ((px - ax).powi(2) + (py - ay).powi(2)).sqrt();
+    }
+    ((bx - ax) * (ay - py) - (ax - px) * (by - ay)).abs() / length
+}
+
+fn douglas_peucker(points: &[Point], epsilon: f32, output: &mut Vec<Point>) {
+    let (first, last) = (points[0], points[points.len() - 1]);
+
+    let farthest = points
+        .iter()
+        .enumerate()
+        .skip(1)
+        .take(points.len().saturating_sub(2))
+        .map(|(i, &p)| (i, point_line_distance(p, first, last)))
+        .max_by(|a, b| a.1.total_cmp(&b.1));
+
+    match farthest {
+        Some((i, distance)) if distance > epsilon => {
+            douglas_peucker(&points[..=i], epsilon, output);
+            output.pop();
+            douglas_peucker(&points[i..], epsilon, output);
+        }
+        _ => {
+            output.push(first);
+            output.push(last);
+        }
+    }
+}
+
+// Douglas–Peucker simplification of a closed contour. The contour is split at
+// the point farthest from its start so both halves are open polylines.
+pub fn approx_polygon(points: &[Point], epsilon: f32) -> Vec<Point> {
+    if points.len() < 3 {
+        return points.to_vec();
+    }
+
+    let start = points[0];
+    let split = points
+        .iter()
+        .enumerate()
+        .max_by_key(|(_, p)| (p.0 - start.0).pow(2) + (p.1 - start.1).pow(2))
+        .map_or(0, |(i, _)| i);
+    if split == 0 {
+        return vec![start];
+    }
+
+    let mut closed = points.to_vec();
+    closed.push(start);
+
+    let mut output = Vec::new();
+    douglas_peucker(&closed[..=split], epsilon, &mut output);
+    output.pop();
+    douglas_peucker(&closed[split..], epsilon, &mut output);
+    output.pop();
+
+    output
+}
+
+fn cross(o: Point, a: Point, b: Point) -> i64 {
+    (a.0 - o.0) as i64 * (b.1 - o.1) as i64 - (a.1 - o.1) as i64 * (b.0 - o.0) as i64
+}
+
+// Andrew's monotone chain.
+pub fn convex_hull(points: &[Point]) -> Vec<Point> {
+    let mut sorted = points.to_vec();
+    sorted.sort();
+    sorted.dedup();
+    if sorted.len() < 3 {
+        return sorted;
+    }
+
+    let mut hull: Vec<Point> = Vec::with_capacity(sorted.len() * 2);
+    for pass in 0..2 {
+        let floor = hull.len();
+        let iter: Box<dyn Iterator<Item = &Point>> = if pass == 0 {
+            Box::new(sorted.iter())
+        } else {
+            Box::new(sorted.iter().rev())
+        };
+        for &p in iter {
+            while hull.len() >= floor + 2 && cross(hull[hull.len() - 2], hull[hull.len() - 1], p) <= 0 {
+                hull.pop();
+            }
+            hull.push(p);
+        }
+        hull.pop();
+    }
+
+    hull
+}
+
+// Rotating calipers: the minimum area rectangle has one side flush with an
+// edge of the convex hull.
+pub fn min_area_rect(points: &[Point]) -> RotatedRect {
+    let hull = convex_hull(points);
+
+    if hull.len() == 1 {
+        return RotatedRect { center: to_f32(hull[0]), size: (0.0, 0.0), angle: 0.0 };
+    }
+
+    let mut best: Option<(f32, RotatedRect)> = None;
+    for i in 0..hull.len() {
+        let (ax, ay) = to_f32(hull[i]);
+        let (bx, by) = to_f32(hull[(i + 1) % hull.len()]);
+        let length = ((bx - ax).powi(2) + (by - ay).powi(2)).sqrt();
+        if length == 0.0 {
+            continue;
+        }
+        let u = ((bx - ax) / length, (by - ay) / length);
+        let v = (-u.1, u.0);
+
+        let (mut min_u, mut max_u, mut min_v, mut max_v) = (f32::MAX, f32::MIN, f32::MAX, f32::MIN);
+        for &p in &hull {
+            let (px, py) = (p.0 as f32 - ax, p.1 as f32 - ay);
+            let pu = px * u.0 + py * u.1;
+            let pv = px * v.0 + py * v.1;
+            min_u = min_u.min(pu);
+            max_u = max_u.max(pu);
+            min_v = min_v.min(pv);
+            max_v = max_v.max(pv);
+        }
+
+        let area = (max_u - min_u) * (max_v - min_v);
+        if best.as_ref().is_none_or(|(best_area, _)| area < *best_area) {
+            let mid_u = (min_u + max_u) / 2.0;
+            let mid_v = (min_v + max_v) / 2.0;
+            let rect = RotatedRect {
+                center: (ax + u.0 * mid_u + v.0 * mid_v, ay + u.1 * mid_u + v.1 * mid_v),
+                size: (max_u - min_u, max_v - min_v),
+                angle: u.1.atan2(u.0).to_degrees(),
+            };
+            best = Some((area, rect));
+        }
+    }
+
+    best.map_or(RotatedRect { center: to_f32(hull[0]), size: (0.0, 0.0), angle: 0.0 }, |(_, rect)| rect)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shape {
+    Contour,
+    Polygon,
+    Hull,
+    Rect,
+}
+
+#[derive(Debug, Clone)]
+pub struct ContourOptions {
+    // Fixed binarisation level, Otsu's level is used when unset.
+    pub threshold: Option<u8>,
+    pub invert: bool,
+    pub shape: Shape,
+    // Douglas–Peucker tolerance as a fraction of the contour perimeter.
+    pub epsilon: f32,
+    pub min_area: f32,
+}
+
+impl Default for ContourOptions {
+    fn default() -> ContourOptions {
+        ContourOptions {
+            threshold: None,
+            invert: false,
+            shape: Shape::Polygon,
+            epsilon: 0.01,
+            min_area: 20.0,
+        }
+    }
+}
+
+impl ContourOptions {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<ContourOptions, Error> {
+        let mut options = ContourOptions::default();
+
+        if let Some(threshold) = params.get("threshold") {
+            options.threshold = helper::threshold_level(threshold)?;
+        }
+        if let Some(invert) = params.get("invert") {
+            options.invert = invert.parse()?;
+        }
+        if let Some(shape) = params.get("shape") {
+            options.shape = match shape.as_str() {
+                "contour" => Shape::Contour,
+                "polygon" => Shape::Polygon,
+                "hull" => Shape::Hull,
+                "rect" => Shape::Rect,
+                other => return Err(anyhow!("Unknown contour shape '{}'", other)),
+            };
+        }
+        if let Some(epsilon) = params.get("epsilon") {
+            options.epsilon = epsilon.parse()?;
+        }
+        if let Some(min_area) = params.get("min_area") {
+            options.min_area = min_area.parse()?;
+        }
+
+        Ok(options)
+    }
+}
+
+// Binarises the frame and draws the requested outline of every contour on
+// top of it, outer borders in green and holes in red.
+pub fn draw_contours(frame: &RgbImage, options: &ContourOptions) -> Result<RgbImage, Error> {
+    let binary = vision::CompVision::binarize(DynamicImage::ImageRgb8(frame.clone()), options.threshold, options.invert)?;
+
+    let mut test_img = frame.clone();
+    for contour in find_contours(&binary) {
+        if contour_area(&contour.points) < options.min_area {
+            continue;
+        }
+
+        let outline: Vec<(f32, f32)> = match options.shape {
+            Shape::Contour => contour.points.iter().map(|&p| to_f32(p)).collect(),
+            Shape::Polygon => {
+                let epsilon = options.epsilon * contour_perimeter(&contour.points);
+                approx_polygon(&contour.points, epsilon).into_iter().map(to_f32).collect()
+            }
+            Shape::Hull => convex_hull(&contour.points).into_iter().map(to_f32).collect(),
+            Shape::Rect => min_area_rect(&contour.points).corners().to_vec(),
+        };
+
+        let color = if contour.is_hole { Rgb([255, 0, 0]) } else { Rgb([0, 255, 0]) };
+        draw::draw_polygon(&mut test_img, &outline, color, 1);
+    }
+
+    Ok(test_img)
+}
+
+impl Filter for ContourOptions {
+    fn name() -> &'static str { "contours" }
+    fn label() -> &'static str { "Contours" }
+
+    fn params() -> Vec<Param> {
+        let defaults = ContourOptions::default();
+        vec![
+            Param::text("threshold", "otsu", "Binarisation level, or otsu"),
+            Param::bool("invert", defaults.invert, "Trace dark objects on a light background"),
+            Param::choice("shape", &["contour", "polygon", "hull", "rect"], "polygon", "What to draw for each contour"),
+            Param::float("epsilon", defaults.epsilon, "Polygon tolerance as a fraction of the perimeter"),
+            Param::float("min_area", defaults.min_area, "Smallest contour area drawn"),
+        ]
+    }
+
+    fn from_params(params: &HashMap<String, String>) -> Result<ContourOptions, Error> {
+        ContourOptions::from_params(params)
+    }
+
+    fn apply(&mut self, frame: &RgbImage) -> Result<RgbImage, Error> {
+        draw_contours(frame, self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_contour_hierarchy_and_shapes() {
+        // A 10x10 square with a 4x4 hole, and a separate blob inside the hole.
+        let binary = GrayImage::from_fn(20, 20, |x, y| {
+            let square = (2..12).contains(&x) && (2..12).contains(&y);
+            let hole = (5..9).contains(&x) && (5..9).contains(&y);
+            let blob = x == 6 && y == 6;
+            image::Luma([if (square && !hole) || blob { 255 } else { 0 }])
+        });
+
+        let contours = find_contours(&binary);
+
+        assert_eq!(contours.len(), 3);
+        assert!(!contours[0].is_hole);
+        assert_eq!(contours[0].parent, None);
+        assert!(contours[1].is_hole);
+        assert_eq!(contours[1].parent, Some(0));
+        assert!(!contours[2].is_hole);
+        assert_eq!(contours[2].parent, Some(1));
+        assert_eq!(contours[2].points, vec![(6, 6)]);
+
+        let outer = &contours[0].points;
+        assert_eq!(contour_area(outer), 81.0);
+        assert_eq!(contour_perimeter(outer), 36.0);
+        assert_eq!(approx_polygon(outer, 0.5).len(), 4);
+        assert_eq!(convex_hull(outer).len(), 4);
+    }
+
+    #[test]
+    pub fn test_min_area_rect_of_diamond() {
+        let diamond = [(0, 5), (5, 0), (10, 5), (5, 10)];
+
+        let rect = min_area_rect(&diamond);
+
+        assert!((rect.size.0 * rect.size.1 - 50.0).abs() < 1e-3);
+        assert!((rect.center.0 - 5.0).abs() < 1e-3 && (rect.center.1 - 5.0).abs() < 1e-3);
+        assert!((rect.angle.abs() % 90.0 - 45.0).abs() < 1e-3);
+    }
+}
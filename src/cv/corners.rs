@@ -0,0 +1,249 @@
+use crate::cv::{vision, draw};
+use crate::cv::filter::{Filter, Param};
+use anyhow::{anyhow, Error};
+use image::{DynamicImage, GrayImage, Rgb, RgbImage};
+use serde::Serialize;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CornerMethod {
+    Harris,
+    ShiTomasi,
+}
+
+#[derive(Debug, Clone)]
+pub struct CornerOptions {
+    pub method: CornerMethod,
+    // Side of the square window the structure tensor is summed over, odd
+    // and between 3 and 31.
+    pub window: u32,
+    // Harris sensitivity, only used by the Harris response.
+    pub k: f32,
+    // Corners weaker than this fraction of the strongest one are dropped.
+    pub quality_level: f32,
+    pub min_distance: f32,
+    // 0 keeps every corner that passes the other criteria.
+    pub max_corners: usize,
+}
+
+impl Default for CornerOptions {
+    fn default() -> CornerOptions {
+        CornerOptions {
+            method: CornerMethod::ShiTomasi,
+            window: 3,
+            k: 0.04,
+            quality_level: 0.01,
+            min_distance: 10.0,
+            max_corners: 100,
+        }
+    }
+}
+
+impl CornerOptions {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<CornerOptions, Error> {
+        let mut options = CornerOptions::default();
+
+        if let Some(method) = params.get("method") {
+            options.method = match method.as_str() {
+                "harris" => CornerMethod::Harris,
+                "shi-tomasi" => CornerMethod::ShiTomasi,
+                other => return Err(anyhow!("Unknown corner method '{}'", other)),
+            };
+        }
+        if let Some(window) = params.get("window") {
+            options.window = window.parse()?;
+            if options.window % 2 == 0 || !(3..=31).contains(&options.window) {
+                return Err(anyhow!("window must be odd and between 3 and 31, got {}", options.window));
+            }
+        }
+        if let Some(k) = params.get("k") {
+            options.k = k.parse()?;
+        }
+        if let Some(quality_level) = params.get("quality_level") {
+            options.quality_level = quality_level.parse()?;
+            if !(0.0..=1.0).contains(&options.quality_level) {
+                return Err(anyhow!("quality_level must be between 0 and 1"));
+            }
+        }
+        if let Some(min_distance) = params.get("min_distance") {
+            options.min_distance = min_distance.parse()?;
+        }
+        if let Some(max_corners) = params.get("max_corners") {
+            options.max_corners = max_corners.parse()?;
+        }
+
+        Ok(options)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Corner {
+    pub x: u32,
+    pub y: u32,
+    pub response: f32,
+}
+
+// Per-pixel corner response from the structure tensor of the Sobel gradients.
+pub fn corner_response(gray: &GrayImage, options: &CornerOptions) -> Vec<f32> {
+    let gradients = vision::CompVision::sobel_gradients(gray);
+    let (width, height) = (gradients.width as usize, gradients.height as usize);
+
+    let products: Vec<(f32, f32, f32)> = gradients
+        .gx
+        .iter()
+        .zip(&gradients.gy)
+        .map(|(&gx, &gy)| (gx * gx, gy * gy, gx * gy))
+        .collect();
+
+    let radius = (options.window / 2) as isize;
+    let mut response = vec![0f32; width * height];
+
+    for y in 0..height {
+        for x in 0..width {
+            let (mut sxx, mut syy, mut sxy) = (0.0, 0.0, 0.0);
+            for dy in -radius..=radius {
+                for dx in -radius..=radius {
+                    let (nx, ny) = (x as isize + dx, y as isize + dy);
+                    if nx < 0 || ny < 0 || nx >= width as isize || ny >= height as isize {
+                        continue;
+                    }
+                    let (xx, yy, xy) = products[ny as usize * width + nx as usize];
+                    sxx += xx;
+                    syy += yy;
+                    sxy += xy;
+                }
+            }
+
+            let trace = sxx + syy;
+            let det = sxx * syy - sxy * sxy;
+            response[y * width + x] = match options.method {
+                CornerMethod::Harris => det - options.k * trace * trace,
+                // Smaller eigenvalue of the 2x2 tensor.
+                CornerMethod::ShiTomasi => trace / 2.0 - ((sxx - syy).powi(2) / 4.0 + sxy * sxy).sqrt(),
+            };
+        }
+    }
+
+    response
+}
+
+// Local maxima of the response above the quality level, strongest first,
+// thinned out so no two corners are closer than `min_distance`.
+pub fn detect_corners(gray: &GrayImage, options: &CornerOptions) -> Vec<Corner> {
+    let (width, height) = gray.dimensions();
+    let response = corner_response(gray, options);
+
+    let max = response.iter().cloned().fold(0.0, f32::max);
+    if max <= 0.0 {
+        return Vec::new();
+    }
+    let threshold = max * options.quality_level;
+
+    let at = |x: u32, y: u32| response[(y * width + x) as usize];
+    let mut candidates = Vec::new();
+    for y in 1..height.saturating_sub(1) {
+        for x in 1..width.saturating_sub(1) {
+            let value = at(x, y);
+            if value <= threshold {
+                continue;
+            }
+            let is_peak = (y - 1..=y + 1)
+                .flat_map(|ny| (x - 1..=x + 1).map(move |nx| (nx, ny)))
+                .all(|(nx, ny)| at(nx, ny) <= value);
+            if is_peak {
+                candidates.push(Corner { x, y, response: value });
+            }
+        }
+    }
+    candidates.sort_by(|a, b| b.response.total_cmp(&a.response));
+
+    let min_distance = options.min_distance * options.min_distance;
+    let mut corners: Vec<Corner> = Vec::new();
+    for candidate in candidates {
+        if options.max_corners > 0 && corners.len() >= options.max_corners {
+            break;
+        }
+        let too_close = corners.iter().any(|corner| {
+            let dx = corner.x as f32 - candidate.x as f32;
+            let dy = corner.y as f32 - candidate.y as f32;
+            dx * dx + dy * dy < min_distance
+        });
+        if !too_close {
+            corners.push(candidate);
+        }
+    }
+
+    corners
+}
+
+pub fn draw_corners(frame: &RgbImage, options: &CornerOptions) -> Result<RgbImage, Error> {
+    let gray = vision::CompVision::to_grayscale(DynamicImage::ImageRgb8(frame.clone()))?;
+
+    let mut test_img = frame.clone();
+    for corner in detect_corners(&gray, options) {
+        draw::draw_circle(&mut test_img, (corner.x as f32, corner.y as f32), 3.0, Rgb([0, 255, 0]));
+    }
+
+    Ok(test_img)
+}
+
+impl Filter for CornerOptions {
+    fn name() -> &'static str { "corners" }
+    fn label() -> &'static str { "Corners" }
+
+    fn params() -> Vec<Param> {
+        let defaults = CornerOptions::default();
+        vec![
+            Param::choice("method", &["harris", "shi-tomasi"], "shi-tomasi", "Corner response"),
+            Param::int("window", defaults.window, "Structure tensor window, odd, 3-31"),
+            Param::float("k", defaults.k, "Harris sensitivity"),
+            Param::float("quality_level", defaults.quality_level, "Fraction of the strongest response kept"),
+            Param::float("min_distance", defaults.min_distance, "Minimum distance between corners"),
+            Param::int("max_corners", defaults.max_corners, "Most corners per frame, 0 for all"),
+        ]
+    }
+
+    fn from_params(params: &HashMap<String, String>) -> Result<CornerOptions, Error> {
+        CornerOptions::from_params(params)
+    }
+
+    fn apply(&mut self, frame: &RgbImage) -> Result<RgbImage, Error> {
+        draw_corners(frame, self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_square_has_four_corners() {
+        let gray = GrayImage::from_fn(40, 40, |x, y| {
+            image::Luma([if (10..30).contains(&x) && (10..30).contains(&y) { 255 } else { 0 }])
+        });
+
+        for method in [CornerMethod::Harris, CornerMethod::ShiTomasi] {
+            let options = CornerOptions { method, quality_level: 0.1, ..CornerOptions::default() };
+            let corners = detect_corners(&gray, &options);
+
+            assert_eq!(corners.len(), 4, "{:?}: {:?}", method, corners);
+            for corner in corners {
+                let near = |v: u32| v.abs_diff(10) <= 1 || v.abs_diff(29) <= 1;
+                assert!(near(corner.x) && near(corner.y), "{:?}: {:?}", method, corner);
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_window_is_odd_and_bounded() {
+        let window = |value: &str| {
+            let params = [("window".to_string(), value.to_string())].into_iter().collect();
+            CornerOptions::from_params(&params).map(|options| options.window)
+        };
+
+        assert_eq!(window("5").unwrap(), 5);
+        for value in ["4", "1", "33", "4000000001"] {
+            assert!(window(value).is_err(), "{}", value);
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use image::{Rgb, RgbImage};
+
+// Small drawing helpers shared by the overlay filters. Anything that falls
+// outside of the image is clipped.
+
+pub fn put_pixel_checked(img: &mut RgbImage, x: i64, y: i64, color: Rgb<u8>) {
+    if x >= 0 && y >= 0 && x < img.width() as i64 && y < img.height() as i64 {
+        img.put_pixel(x as u32, y as u32, color);
+    }
+}
+
+// Bresenham line, `thickness` grows the line into a square brush.
+pub fn draw_line(img: &mut RgbImage, from: (f32, f32), to: (f32, f32), color: Rgb<u8>, thickness: u32) {
+    let (mut x0, mut y0) = (from.0.round() as i64, from.1.round() as i64);
+    let (x1, y1) = (to.0.round() as i64, to.1.round() as i64);
+
+    let dx = (x1 - x0).abs();
+    let dy = -(y1 - y0).abs();
+    let sx = if x0 < x1 { 1 } else { -1 };
+    let sy = if y0 < y1 { 1 } else { -1 };
+    let mut err = dx + dy;
+
+    let half = thickness as i64 / 2;
+    let extra = (thickness as i64 - 1) - half;
+
+    loop {
+        for by in -half..=extra {
+            for bx in -half..=extra {
+                put_pixel_checked(img, x0 + bx, y0 + by, color);
+            }
+        }
+
+        if x0 == x1 && y0 == y1 {
+            break;
+        }
+        let e2 = 2 * err;
+        if e2 >= dy {
+            err += dy;
+            x0 += sx;
+        }
+        if e2 <= dx {
+            err += dx;
+            y0 += sy;
+        }
+    }
+}
+
+// Draws a closed polygon through `points`.
+pub fn draw_polygon(img: &mut RgbImage, points: &[(f32, f32)], color: Rgb<u8>, thickness: u32) {
+    for (i, &point) in points.iter().enumerate() {
+        let next = points[(i + 1) % points.len()];
+        draw_line(img, point, next, color, thickness);
+    }
+}
+
+// Midpoint circle outline.
+pub fn draw_circle(img: &mut RgbImage, center: (f32, f32), radius: f32, color: Rgb<u8>) {
+    let (cx, cy) = (center.0.round() as i64, center.1.round() as i64);
+    let radius = radius.round() as i64;
+
+    let (mut x, mut y) = (radius, 0);
+    let mut err = 1 - radius;
+
+    while x >= y {
+        for (dx, dy) in [(x, y), (y, x), (-y, x), (-x, y), (-x, -y), (-y, -x), (y, -x), (x, -y)] {
+            put_pixel_checked(img, cx + dx, cy + dy, color);
+        }
+
+        y += 1;
+        if err < 0 {
+            err += 2 * y + 1;
+        } else {
+            x -= 1;
+            err += 2 * (y - x) + 1;
+        }
+    }
+}
@@ -0,0 +1,425 @@
+use crate::cv::{vision, draw};
+use crate::cv::corners::{self, CornerMethod, CornerOptions};
+use crate::cv::filter::{Filter, Param};
+use anyhow::{anyhow, Error};
+use image::{imageops, DynamicImage, GrayImage, ImageBuffer, Rgb, RgbImage};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+#[derive(Debug, Clone)]
+pub struct OrbOptions {
+    pub features: usize,
+    pub fast_threshold: u8,
+    pub levels: u32,
+    pub scale_factor: f32,
+    // Lowe's ratio test: the best match has to be clearly better than the
+    // runner up, otherwise the match is ambiguous and dropped.
+    pub ratio: f32,
+}
+
+impl Default for OrbOptions {
+    fn default() -> OrbOptions {
+        OrbOptions {
+            features: 500,
+            fast_threshold: 20,
+            levels: 4,
+            scale_factor: 1.2,
+            ratio: 0.8,
+        }
+    }
+}
+
+impl OrbOptions {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<OrbOptions, Error> {
+        let mut options = OrbOptions::default();
+
+        if let Some(features) = params.get("features") {
+            options.features = features.parse()?;
+        }
+        if let Some(fast_threshold) = params.get("fast_threshold") {
+            options.fast_threshold = fast_threshold.parse()?;
+        }
+        if let Some(levels) = params.get("levels") {
+            options.levels = levels.parse()?;
+            if options.levels == 0 {
+                return Err(anyhow!("levels must be at least 1"));
+            }
+        }
+        if let Some(scale_factor) = params.get("scale_factor") {
+            options.scale_factor = scale_factor.parse()?;
+            if options.scale_factor <= 1.0 {
+                return Err(anyhow!("scale_factor must be greater than 1"));
+            }
+        }
+        if let Some(ratio) = params.get("ratio") {
+            options.ratio = ratio.parse()?;
+            if !(0.0..=1.0).contains(&options.ratio) {
+                return Err(anyhow!("ratio must be between 0 and 1"));
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Keypoint {
+    // Position in full resolution image coordinates.
+    pub x: f32,
+    pub y: f32,
+    pub level: u32,
+    // Orientation in radians from the intensity centroid.
+    pub angle: f32,
+    pub response: f32,
+}
+
+pub type Descriptor = [u64; 4];
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Match {
+    pub query: usize,
+    pub train: usize,
+    pub distance: u32,
+}
+
+// Bresenham circle of radius 3 used by FAST, clockwise from the top.
+const FAST_CIRCLE: [(i32, i32); 16] = [
+    (0, -3), (1, -3), (2, -2), (3, -1), (3, 0), (3, 1), (2, 2), (1, 3),
+    (0, 3), (-1, 3), (-2, 2), (-3, 1), (-3, 0), (-3, -1), (-2, -2), (-1, -3),
+];
+const FAST_ARC: usize = 9;
+
+// Keypoints need the whole rotated BRIEF patch inside the image.
+const PATCH_RADIUS: i32 = 15;
+const BORDER: u32 = PATCH_RADIUS as u32 + 1;
+
+// FAST-9 score of a pixel, or `None` when fewer than 9 contiguous circle
+// pixels are all brighter or all darker than the centre by `threshold`.
+fn fast_score(gray: &GrayImage, x: u32, y: u32, threshold: u8) -> Option<f32> {
+    let centre = gray.get_pixel(x, y).0[0] as i32;
+    let threshold = threshold as i32;
+    let at = |i: usize| {
+        let (dx, dy) = FAST_CIRCLE[i];
+        gray.get_pixel((x as i32 + dx) as u32, (y as i32 + dy) as u32).0[0] as i32 - centre
+    };
+
+    // Any arc of 9 covers at least two of the four compass points.
+    let compass = [at(0), at(4), at(8), at(12)];
+    let brighter = compass.iter().filter(|&&d| d > threshold).count();
+    let darker = compass.iter().filter(|&&d| d < -threshold).count();
+    if brighter < 2 && darker < 2 {
+        return None;
+    }
+
+    let differences: Vec<i32> = (0..16).map(at).collect();
+    for sign in [1, -1] {
+        let mut run = 0;
+        for i in 0..32 {
+            if sign * differences[i % 16] > threshold {
+                run += 1;
+                if run >= FAST_ARC {
+                    let score = differences
+                        .iter()
+                        .map(|&d| (sign * d - threshold).max(0))
+                        .sum::<i32>();
+                    return Some(score as f32);
+                }
+            } else {
+                run = 0;
+            }
+        }
+    }
+
+    None
+}
+
+// FAST-9 corners with 3x3 non maximum suppression on the FAST score.
+pub fn detect_fast(gray: &GrayImage, threshold: u8, border: u32) -> Vec<(u32, u32)> {
+    let (width, height) = gray.dimensions();
+    let border = border.max(3);
+    if width <= 2 * border || height <= 2 * border {
+        return Vec::new();
+    }
+
+    let mut scores = vec![0f32; (width * height) as usize];
+    for y in border..height - border {
+        for x in border..width - border {
+            if let Some(score) = fast_score(gray, x, y, threshold) {
+                scores[(y * width + x) as usize] = score;
+            }
+        }
+    }
+
+    let at = |x: u32, y: u32| scores[(y * width + x) as usize];
+    let mut keypoints = Vec::new();
+    for y in border..height - border {
+        for x in border..width - border {
+            let score = at(x, y);
+            if score == 0.0 {
+                continue;
+            }
+            let is_peak = (y - 1..=y + 1)
+                .flat_map(|ny| (x - 1..=x + 1).map(move |nx| (nx, ny)))
+                .filter(|&(nx, ny)| (nx, ny) != (x, y))
+                .all(|(nx, ny)| at(nx, ny) < score || (at(nx, ny) == score && (ny, nx) > (y, x)));
+            if is_peak {
+                keypoints.push((x, y));
+            }
+        }
+    }
+
+    keypoints
+}
+
+// Orientation of the patch from its intensity centroid (Rosin).
+fn intensity_angle(gray: &GrayImage, x: u32, y: u32) -> f32 {
+    let (mut m01, mut m10) = (0f32, 0f32);
+    for dy in -PATCH_RADIUS..=PATCH_RADIUS {
+        for dx in -PATCH_RADIUS..=PATCH_RADIUS {
+            if dx * dx + dy * dy > PATCH_RADIUS * PATCH_RADIUS {
+                continue;
+            }
+            let value = gray.get_pixel((x as i32 + dx) as u32, (y as i32 + dy) as u32).0[0] as f32;
+            m10 += dx as f32 * value;
+            m01 += dy as f32 * value;
+        }
+    }
+    m01.atan2(m10)
+}
+
+// 256 point pairs drawn from an isotropic Gaussian around the keypoint and
+// kept inside the patch circle, so any rotation stays within the border.
+fn brief_pattern() -> &'static Vec<[(f32, f32); 2]> {
+    static PATTERN: OnceLock<Vec<[(f32, f32); 2]>> = OnceLock::new();
+    PATTERN.get_or_init(|| {
+        let mut rng = StdRng::seed_from_u64(0x0b5e_55ed);
+        let radius = PATCH_RADIUS as f32 - 0.5;
+        let mut point = || loop {
+            // Sum of uniforms, close enough to Gaussian with sigma ~ radius / 2.5.
+            let sample = |rng: &mut StdRng| (0..4).map(|_| rng.gen_range(-1.0..1.0)).sum::<f32>() * radius / 3.5;
+            let p = (sample(&mut rng), sample(&mut rng));
+            if p.0 * p.0 + p.1 * p.1 <= radius * radius {
+                return p;
+            }
+        };
+        (0..256).map(|_| [point(), point()]).collect()
+    })
+}
+
+// Steered BRIEF: the sampling pattern is rotated by the keypoint angle.
+fn describe(smoothed: &GrayImage, x: u32, y: u32, angle: f32) -> Descriptor {
+    let (sin, cos) = angle.sin_cos();
+    let sample = |(px, py): (f32, f32)| {
+        let rx = (px * cos - py * sin).round() as i32;
+        let ry = (px * sin + py * cos).round() as i32;
+        smoothed.get_pixel((x as i32 + rx) as u32, (y as i32 + ry) as u32).0[0]
+    };
+
+    let mut descriptor = [0u64; 4];
+    for (i, pair) in brief_pattern().iter().enumerate() {
+        if sample(pair[0]) < sample(pair[1]) {
+            descriptor[i / 64] |= 1 << (i % 64);
+        }
+    }
+    descriptor
+}
+
+// ORB: FAST keypoints over an image pyramid, ranked by their Harris response,
+// with intensity centroid orientation and steered BRIEF descriptors.
+pub fn detect_and_compute(gray: &GrayImage, options: &OrbOptions) -> (Vec<Keypoint>, Vec<Descriptor>) {
+    let harris = CornerOptions { method: CornerMethod::Harris, window: 7, ..CornerOptions::default() };
+    let mut candidates = Vec::new();
+    let mut smoothed_levels = Vec::new();
+
+    for level in 0..options.levels {
+        let scale = options.scale_factor.powi(level as i32);
+        let width = (gray.width() as f32 / scale).round() as u32;
+        let height = (gray.height() as f32 / scale).round() as u32;
+        if width <= 2 * BORDER || height <= 2 * BORDER {
+            break;
+        }
+
+        let image = if level == 0 {
+            gray.clone()
+        } else {
+            imageops::resize(gray, width, height, imageops::FilterType::Triangle)
+        };
+        let response = corners::corner_response(&image, &harris);
+
+        for (x, y) in detect_fast(&image, options.fast_threshold, BORDER) {
+            candidates.push((level, x, y, response[(y * width + x) as usize], intensity_angle(&image, x, y)));
+        }
+        smoothed_levels.push(imageops::blur(&image, 2.0));
+    }
+
+    candidates.sort_by(|a, b| b.3.total_cmp(&a.3));
+    candidates.truncate(options.features);
+
+    candidates
+        .into_iter()
+        .map(|(level, x, y, response, angle)| {
+            let scale = options.scale_factor.powi(level as i32);
+            let keypoint = Keypoint { x: x as f32 * scale, y: y as f32 * scale, level, angle, response };
+            (keypoint, describe(&smoothed_levels[level as usize], x, y, angle))
+        })
+        .unzip()
+}
+
+pub fn hamming(a: &Descriptor, b: &Descriptor) -> u32 {
+    a.iter().zip(b).map(|(x, y)| (x ^ y).count_ones()).sum()
+}
+
+// Brute force matching of every query descriptor against all train
+// descriptors, keeping matches that pass the ratio test.
+pub fn match_descriptors(query: &[Descriptor], train: &[Descriptor], ratio: f32) -> Vec<Match> {
+    let mut matches = Vec::new();
+
+    for (i, descriptor) in query.iter().enumerate() {
+        let (mut best, mut second) = ((u32::MAX, 0), u32::MAX);
+        for (j, candidate) in train.iter().enumerate() {
+            let distance = hamming(descriptor, candidate);
+            if distance < best.0 {
+                second = best.0;
+                best = (distance, j);
+            } else if distance < second {
+                second = distance;
+            }
+        }
+
+        if best.0 != u32::MAX && (second == u32::MAX || (best.0 as f32) < ratio * second as f32) {
+            matches.push(Match { query: i, train: best.1, distance: best.0 });
+        }
+    }
+
+    matches
+}
+
+fn match_color(i: usize) -> Rgb<u8> {
+    const COLORS: [[u8; 3]; 6] = [[255, 64, 64], [64, 255, 64], [64, 128, 255], [255, 255, 64], [255, 64, 255], [64, 255, 255]];
+    Rgb(COLORS[i % COLORS.len()])
+}
+
+// Puts both images side by side and connects every match with a line.
+pub fn draw_matches(
+    left: &RgbImage,
+    left_keypoints: &[Keypoint],
+    right: &RgbImage,
+    right_keypoints: &[Keypoint],
+    matches: &[Match],
+) -> RgbImage {
+    let width = left.width() + right.width();
+    let height = left.height().max(right.height());
+    let mut canvas = ImageBuffer::new(width, height);
+    imageops::replace(&mut canvas, left, 0, 0);
+    imageops::replace(&mut canvas, right, left.width() as i64, 0);
+
+    let offset = left.width() as f32;
+    for (i, m) in matches.iter().enumerate() {
+        let a = left_keypoints[m.query];
+        let b = right_keypoints[m.train];
+        let color = match_color(i);
+        draw::draw_circle(&mut canvas, (a.x, a.y), 3.0, color);
+        draw::draw_circle(&mut canvas, (b.x + offset, b.y), 3.0, color);
+        draw::draw_line(&mut canvas, (a.x, a.y), (b.x + offset, b.y), color, 1);
+    }
+
+    canvas
+}
+
+pub fn match_images(left_path: &str, right_path: &str, options: &OrbOptions) -> Result<RgbImage, Error>{
+    let left = vision::CompVision::new(left_path)?.image;
+    let right = vision::CompVision::new(right_path)?.image;
+
+    let (left_keypoints, left_descriptors) = detect_and_compute(&left.to_luma8(), options);
+    let (right_keypoints, right_descriptors) = detect_and_compute(&right.to_luma8(), options);
+    let matches = match_descriptors(&left_descriptors, &right_descriptors, options.ratio);
+
+    Ok(draw_matches(&left.to_rgb8(), &left_keypoints, &right.to_rgb8(), &right_keypoints, &matches))
+}
+
+// Draws the keypoints of every frame and a track from where each matched
+// keypoint was in the previous frame.
+pub struct FeatureTracks {
+    options: OrbOptions,
+    previous: Option<(Vec<Keypoint>, Vec<Descriptor>)>,
+}
+
+impl Filter for FeatureTracks {
+    fn name() -> &'static str { "features" }
+    fn label() -> &'static str { "ORB Features" }
+
+    fn params() -> Vec<Param> {
+        let defaults = OrbOptions::default();
+        vec![
+            Param::int("features", defaults.features, "Most keypoints per frame"),
+            Param::int("fast_threshold", defaults.fast_threshold, "FAST intensity threshold"),
+            Param::int("levels", defaults.levels, "Pyramid levels"),
+            Param::float("scale_factor", defaults.scale_factor, "Scale between pyramid levels"),
+            Param::float("ratio", defaults.ratio, "Ratio test for matches"),
+        ]
+    }
+
+    fn from_params(params: &HashMap<String, String>) -> Result<FeatureTracks, Error> {
+        Ok(FeatureTracks { options: OrbOptions::from_params(params)?, previous: None })
+    }
+
+    // Tracks are matched against the previous frame.
+    fn stateful(&self) -> bool { true }
+
+    fn apply(&mut self, frame: &RgbImage) -> Result<RgbImage, Error> {
+        let gray = vision::CompVision::to_grayscale(DynamicImage::ImageRgb8(frame.clone()))?;
+        let (keypoints, descriptors) = detect_and_compute(&gray, &self.options);
+
+        let mut test_img = frame.clone();
+        for keypoint in &keypoints {
+            draw::draw_circle(&mut test_img, (keypoint.x, keypoint.y), 2.0, Rgb([0, 255, 0]));
+        }
+        if let Some((previous_keypoints, previous_descriptors)) = &self.previous {
+            for m in match_descriptors(&descriptors, previous_descriptors, self.options.ratio) {
+                let (a, b) = (keypoints[m.query], previous_keypoints[m.train]);
+                draw::draw_line(&mut test_img, (b.x, b.y), (a.x, a.y), Rgb([255, 255, 0]), 1);
+            }
+        }
+
+        self.previous = Some((keypoints, descriptors));
+        Ok(test_img)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn textured(width: u32, height: u32, shift: (u32, u32)) -> GrayImage {
+        let mut rng = StdRng::seed_from_u64(7);
+        let blocks: Vec<u8> = (0..(width / 6 + 2) * (height / 6 + 2)).map(|_| rng.gen()).collect();
+        GrayImage::from_fn(width, height, |x, y| {
+            let (sx, sy) = ((x + width - shift.0) % width, (y + height - shift.1) % height);
+            image::Luma([blocks[((sy / 6) * (width / 6 + 2) + sx / 6) as usize]])
+        })
+    }
+
+    #[test]
+    pub fn test_orb_matches_shifted_image() {
+        let options = OrbOptions::default();
+        let a = textured(160, 120, (0, 0));
+        let b = textured(160, 120, (5, 3));
+
+        let (keypoints_a, descriptors_a) = detect_and_compute(&a, &options);
+        let (keypoints_b, descriptors_b) = detect_and_compute(&b, &options);
+        let matches = match_descriptors(&descriptors_a, &descriptors_b, options.ratio);
+
+        assert!(matches.len() > 20, "only {} matches", matches.len());
+        let consistent = matches
+            .iter()
+            .filter(|m| {
+                let (p, q) = (keypoints_a[m.query], keypoints_b[m.train]);
+                (q.x - p.x - 5.0).abs() <= 1.5 && (q.y - p.y - 3.0).abs() <= 1.5
+            })
+            .count();
+        assert!(consistent * 10 >= matches.len() * 8, "{} of {} consistent", consistent, matches.len());
+    }
+}
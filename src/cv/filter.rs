@@ -0,0 +1,467 @@
+use crate::cv::{vision, helper, kmeans, motion, contours, components, corners, features, hough, circles, watershed, slic, cartoon, pipeline, stream};
+use anyhow::{anyhow, Error};
+use image::{imageops, DynamicImage, ImageFormat, RgbImage};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::OnceLock;
+use indicatif::{ProgressBar, ProgressStyle};
+use rayon::prelude::*;
+
+// A per-frame video filter. The associated functions describe the filter
+// for the registry, `from_params` configures one instance per job and
+// `apply` is then called on every frame in order, so stateful filters (a
+// background model, the previous frame's keypoints) can keep state in self.
+pub trait Filter: Send {
+    // Identifier used by the API and the GUI dropdown.
+    fn name() -> &'static str where Self: Sized;
+    fn label() -> &'static str where Self: Sized;
+    fn params() -> Vec<Param> where Self: Sized;
+    fn from_params(params: &HashMap<String, String>) -> Result<Self, Error> where Self: Sized;
+
+    fn apply(&mut self, frame: &RgbImage) -> Result<RgbImage, Error>;
+
+    // Whether a frame's output depends on the frames before it. Stateful
+    // filters are never split across workers.
+    fn stateful(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", content = "options", rename_all = "lowercase")]
+pub enum ParamKind {
+    Int,
+    Float,
+    Bool,
+    Choice(Vec<&'static str>),
+    Text,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Param {
+    pub name: &'static str,
+    #[serde(flatten)]
+    pub kind: ParamKind,
+    pub default: String,
+    pub description: &'static str,
+}
+
+impl Param {
+    fn new(name: &'static str, kind: ParamKind, default: impl ToString, description: &'static str) -> Param {
+        Param { name, kind, default: default.to_string(), description }
+    }
+
+    pub fn int(name: &'static str, default: impl ToString, description: &'static str) -> Param {
+        Param::new(name, ParamKind::Int, default, description)
+    }
+
+    pub fn float(name: &'static str, default: impl ToString, description: &'static str) -> Param {
+        Param::new(name, ParamKind::Float, default, description)
+    }
+
+    pub fn bool(name: &'static str, default: bool, description: &'static str) -> Param {
+        Param::new(name, ParamKind::Bool, default, description)
+    }
+
+    pub fn choice(name: &'static str, options: &[&'static str], default: &str, description: &'static str) -> Param {
+        Param::new(name, ParamKind::Choice(options.to_vec()), default, description)
+    }
+
+    pub fn text(name: &'static str, default: impl ToString, description: &'static str) -> Param {
+        Param::new(name, ParamKind::Text, default, description)
+    }
+
+    // Type check only, range checks stay in each filter's `from_params`.
+    pub fn validate(&self, value: &str) -> Result<(), Error> {
+        let valid = match &self.kind {
+            ParamKind::Int => value.parse::<i64>().is_ok(),
+            ParamKind::Float => value.parse::<f64>().is_ok(),
+            ParamKind::Bool => value.parse::<bool>().is_ok(),
+            ParamKind::Choice(options) => options.contains(&value),
+            ParamKind::Text => true,
+        };
+        if valid {
+            return Ok(());
+        }
+
+        let expected = match &self.kind {
+            ParamKind::Int => "an integer".to_string(),
+            ParamKind::Float => "a number".to_string(),
+            ParamKind::Bool => "true or false".to_string(),
+            ParamKind::Choice(options) => format!("one of {}", options.join(", ")),
+            ParamKind::Text => unreachable!(),
+        };
+        Err(anyhow!("Invalid value '{}' for '{}', expected {}", value, self.name, expected))
+    }
+}
+
+type Build = fn(&HashMap<String, String>) -> Result<Box<dyn Filter>, Error>;
+
+#[derive(Serialize)]
+pub struct Entry {
+    pub name: &'static str,
+    pub label: &'static str,
+    pub params: Vec<Param>,
+    #[serde(skip)]
+    build: Build,
+}
+
+impl Entry {
+    fn of<F: Filter + 'static>() -> Entry {
+        Entry {
+            name: F::name(),
+            label: F::label(),
+            params: F::params(),
+            build: |params| Ok(Box::new(F::from_params(params)?)),
+        }
+    }
+
+    // Checks the declared parameters that are present and configures a new
+    // instance. Unknown keys are ignored, the same form carries fields for
+    // the runner (stabilize, ...) as well.
+    pub fn build(&self, params: &HashMap<String, String>) -> Result<Box<dyn Filter>, Error> {
+        for param in &self.params {
+            if let Some(value) = params.get(param.name) {
+                param.validate(value).map_err(|e| anyhow!("{}: {}", self.name, e))?;
+            }
+        }
+        (self.build)(params).map_err(|e| anyhow!("{}: {}", self.name, e))
+    }
+}
+
+// Every filter the API and GUI offer, in dropdown order. Adding a filter is
+// one `Filter` impl plus a line here.
+pub fn registry() -> &'static [Entry] {
+    static REGISTRY: OnceLock<Vec<Entry>> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        vec![
+            Entry::of::<Sobel>(),
+            Entry::of::<Grayscale>(),
+            Entry::of::<Posterize>(),
+            Entry::of::<Gaussian>(),
+            Entry::of::<Threshold>(),
+            Entry::of::<kmeans::KMeans>(),
+            Entry::of::<motion::MotionFilter>(),
+            Entry::of::<contours::ContourOptions>(),
+            Entry::of::<components::ComponentOptions>(),
+            Entry::of::<corners::CornerOptions>(),
+            Entry::of::<features::FeatureTracks>(),
+            Entry::of::<hough::HoughOptions>(),
+            Entry::of::<circles::CircleOptions>(),
+            Entry::of::<watershed::WatershedOptions>(),
+            Entry::of::<slic::SlicOptions>(),
+            Entry::of::<cartoon::CartoonOptions>(),
+            Entry::of::<pipeline::Pipeline>(),
+        ]
+    })
+}
+
+pub fn find(name: &str) -> Option<&'static Entry> {
+    registry().iter().find(|entry| entry.name == name)
+}
+
+// Builds a configured filter instance, called once per worker.
+pub type Factory<'a> = dyn Fn() -> Result<Box<dyn Filter>, Error> + Send + Sync + 'a;
+
+// Streams the frames of the video through the filter and encodes the result
+// into `output_path`.
+pub fn run(video_path: &str, output_path: &str, make: &Factory, video: &helper::VideoOptions, workers: usize) -> Result<(), Error>{
+    let reader = stream::FrameReader::open(video_path, video)?;
+    let mut writer = stream::FrameWriter::create(output_path, &reader.format(), video)?;
+
+    println!("Filtering {} ..", video_path);
+
+    let pb = ProgressBar::new(reader.expected_frames);
+    pb.set_style(ProgressStyle::default_bar()
+        .template("{msg} {bar:40.cyan/blue} {percent:>3}%")?
+        .progress_chars("█▌▐"));
+
+    filter_stream(Box::new(reader), make, workers, &mut |frame| {
+        writer.write(&frame)?;
+        pb.inc(1);
+        Ok(())
+    })?;
+
+    writer.finish()
+}
+
+// Still images the filters can be run on directly, without ffmpeg.
+pub const IMAGE_FORMATS: [ImageFormat; 5] = [ImageFormat::Png, ImageFormat::Jpeg, ImageFormat::WebP, ImageFormat::Bmp, ImageFormat::Tiff];
+
+// Image format for a name such as "png" or "jpg".
+pub fn image_format(name: &str) -> Result<ImageFormat, Error> {
+    ImageFormat::from_extension(name)
+        .filter(|format| IMAGE_FORMATS.contains(format))
+        .ok_or_else(|| anyhow!("Unknown image format '{}', expected one of png, jpeg, webp, bmp, tiff", name))
+}
+
+// Decodes an uploaded image and tells which format it was in. Images are
+// held to the same size limit as video frames, checked from the header
+// before anything is decoded.
+pub fn decode_image(data: &[u8]) -> Result<(RgbImage, ImageFormat), Error> {
+    let format = image::guess_format(data)
+        .ok()
+        .filter(|format| IMAGE_FORMATS.contains(format))
+        .ok_or_else(|| anyhow!("Expected a PNG, JPEG, WebP, BMP or TIFF image"))?;
+    let (width, height) = image::ImageReader::with_format(Cursor::new(data), format).into_dimensions()?;
+    if width > helper::MAX_SIDE || height > helper::MAX_SIDE {
+        return Err(anyhow!("Image is {}x{}, larger than {} on a side", width, height, helper::MAX_SIDE));
+    }
+    let image = image::load_from_memory_with_format(data, format)?;
+    Ok((image.to_rgb8(), format))
+}
+
+pub fn encode_image(image: &RgbImage, format: ImageFormat) -> Result<Vec<u8>, Error> {
+    let mut data = Cursor::new(Vec::new());
+    image.write_to(&mut data, format)?;
+    Ok(data.into_inner())
+}
+
+// Filters the frames and hands the results to `sink` in video order.
+// Stateful filters see the frames in order on a single instance; all others
+// run on the shared pool, `workers` frames at a time (0 for one per core),
+// each frame with its own instance. Frames are read one batch at a time, so
+// memory stays bounded however long the video is.
+pub fn filter_stream(frames: stream::Frames, make: &Factory, workers: usize, sink: &mut dyn FnMut(RgbImage) -> Result<(), Error>) -> Result<(), Error>{
+    let mut filter = make()?;
+    if filter.stateful() || workers == 1 {
+        for frame in frames {
+            sink(filter.apply(&frame?)?)?;
+        }
+        return Ok(());
+    }
+
+    let batch = helper::frames_in_flight(workers)?;
+    let mut filters = vec![filter];
+    while filters.len() < batch {
+        filters.push(make()?);
+    }
+
+    let mut frames = frames;
+    loop {
+        let batch = frames
+            .by_ref()
+            .take(filters.len())
+            .collect::<Result<Vec<RgbImage>, Error>>()?;
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let filtered = helper::thread_pool().install(|| {
+            filters
+                .par_iter_mut()
+                .zip(batch.par_iter())
+                .map(|(filter, frame)| filter.apply(frame))
+                .collect::<Result<Vec<RgbImage>, Error>>()
+        })?;
+        for frame in filtered {
+            sink(frame)?;
+        }
+    }
+}
+
+// Filters that are a single CompVision call.
+
+pub struct Sobel;
+
+impl Filter for Sobel {
+    fn name() -> &'static str { "sobel" }
+    fn label() -> &'static str { "Sobel" }
+    fn params() -> Vec<Param> { Vec::new() }
+    fn from_params(_: &HashMap<String, String>) -> Result<Sobel, Error> { Ok(Sobel) }
+
+    fn apply(&mut self, frame: &RgbImage) -> Result<RgbImage, Error> {
+        let edges = vision::CompVision::edge_detection_sobel(DynamicImage::ImageRgb8(frame.clone()))?;
+        Ok(DynamicImage::ImageLuma8(edges).to_rgb8())
+    }
+}
+
+pub struct Grayscale;
+
+impl Filter for Grayscale {
+    fn name() -> &'static str { "grayscale" }
+    fn label() -> &'static str { "GrayScale" }
+    fn params() -> Vec<Param> { Vec::new() }
+    fn from_params(_: &HashMap<String, String>) -> Result<Grayscale, Error> { Ok(Grayscale) }
+
+    fn apply(&mut self, frame: &RgbImage) -> Result<RgbImage, Error> {
+        let gray = vision::CompVision::to_grayscale(DynamicImage::ImageRgb8(frame.clone()))?;
+        Ok(DynamicImage::ImageLuma8(gray).to_rgb8())
+    }
+}
+
+pub struct Posterize {
+    pub levels: usize,
+}
+
+impl Filter for Posterize {
+    fn name() -> &'static str { "posterize" }
+    fn label() -> &'static str { "Posterize" }
+
+    fn params() -> Vec<Param> {
+        vec![Param::int("levels", 5, "Colour levels per channel")]
+    }
+
+    fn from_params(params: &HashMap<String, String>) -> Result<Posterize, Error> {
+        let levels = match params.get("levels") {
+            Some(levels) => levels.parse()?,
+            None => 5,
+        };
+        if !(1..=256).contains(&levels) {
+            return Err(anyhow!("levels must be between 1 and 256"));
+        }
+        Ok(Posterize { levels })
+    }
+
+    fn apply(&mut self, frame: &RgbImage) -> Result<RgbImage, Error> {
+        vision::CompVision::posterize(DynamicImage::ImageRgb8(frame.clone()), self.levels)
+    }
+}
+
+pub struct Gaussian {
+    pub sigma: f32,
+}
+
+impl Filter for Gaussian {
+    fn name() -> &'static str { "gaussian" }
+    fn label() -> &'static str { "Gaussian Blur" }
+
+    fn params() -> Vec<Param> {
+        vec![Param::float("sigma", 1.4, "Standard deviation of the blur")]
+    }
+
+    fn from_params(params: &HashMap<String, String>) -> Result<Gaussian, Error> {
+        let sigma = match params.get("sigma") {
+            Some(sigma) => sigma.parse()?,
+            None => 1.4,
+        };
+        if sigma <= 0.0 {
+            return Err(anyhow!("sigma must be positive"));
+        }
+        Ok(Gaussian { sigma })
+    }
+
+    fn apply(&mut self, frame: &RgbImage) -> Result<RgbImage, Error> {
+        Ok(imageops::blur(frame, self.sigma))
+    }
+}
+
+pub struct Threshold {
+    // `None` picks the level with Otsu on every frame.
+    pub level: Option<u8>,
+    pub invert: bool,
+}
+
+impl Filter for Threshold {
+    fn name() -> &'static str { "threshold" }
+    fn label() -> &'static str { "Threshold" }
+
+    fn params() -> Vec<Param> {
+        vec![
+            Param::text("level", "otsu", "Grey level 0-255, or otsu"),
+            Param::bool("invert", false, "Swap black and white"),
+        ]
+    }
+
+    fn from_params(params: &HashMap<String, String>) -> Result<Threshold, Error> {
+        let level = match params.get("level") {
+            Some(level) => helper::threshold_level(level)?,
+            None => None,
+        };
+        let invert = match params.get("invert") {
+            Some(invert) => invert.parse()?,
+            None => false,
+        };
+        Ok(Threshold { level, invert })
+    }
+
+    fn apply(&mut self, frame: &RgbImage) -> Result<RgbImage, Error> {
+        let binary = vision::CompVision::binarize(DynamicImage::ImageRgb8(frame.clone()), self.level, self.invert)?;
+        Ok(DynamicImage::ImageLuma8(binary).to_rgb8())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_registry_builds_every_filter_with_defaults() {
+        let frame = RgbImage::from_fn(48, 32, |x, y| image::Rgb([(x * 5) as u8, (y * 7) as u8, 128]));
+
+        for entry in registry() {
+            let mut filter = entry.build(&HashMap::new()).unwrap();
+            let out = filter.apply(&frame).unwrap();
+            assert_eq!(out.dimensions(), frame.dimensions(), "{}", entry.name);
+
+            // Declared defaults have to be accepted as explicit values too.
+            let defaults: HashMap<String, String> = entry
+                .params
+                .iter()
+                .map(|param| (param.name.to_string(), param.default.clone()))
+                .collect();
+            entry.build(&defaults).unwrap_or_else(|e| panic!("{}", e));
+        }
+    }
+
+    #[test]
+    pub fn test_params_are_type_checked() {
+        let entry = find("posterize").unwrap();
+        let params = HashMap::from([("levels".to_string(), "many".to_string())]);
+
+        let error = entry.build(&params).err().unwrap().to_string();
+        assert!(error.contains("levels") && error.contains("integer"), "{}", error);
+        assert!(find("nope").is_none());
+
+        let listed = serde_json::to_value(find("cartoon").unwrap()).unwrap();
+        assert_eq!(listed["params"][3]["type"], "choice");
+        assert_eq!(listed["params"][3]["options"], serde_json::json!(["posterize", "kmeans"]));
+        assert_eq!(listed["params"][0]["type"], "int");
+    }
+    #[test]
+    pub fn test_parallel_frames_match_sequential() {
+        let frames = || -> stream::Frames {
+            Box::new((0..12u8).map(|i| Ok(RgbImage::from_fn(32, 24, |x, y| image::Rgb([(x * 8) as u8 ^ i, (y * 10) as u8, i * 20])))))
+        };
+
+        // Cartoon runs on four workers, motion falls back to frame order.
+        for name in ["cartoon", "motion"] {
+            let make = || find(name).unwrap().build(&HashMap::new());
+            let mut sequential = Vec::new();
+            filter_stream(frames(), &make, 1, &mut |frame| {
+                sequential.push(frame);
+                Ok(())
+            })
+            .unwrap();
+            let mut parallel = Vec::new();
+            filter_stream(frames(), &make, 4, &mut |frame| {
+                parallel.push(frame);
+                Ok(())
+            })
+            .unwrap();
+
+            assert_eq!(sequential.len(), 12);
+            assert!(sequential == parallel, "{}", name);
+        }
+    }
+    #[test]
+    pub fn test_images_are_filtered_in_memory() {
+        let frame = RgbImage::from_fn(24, 16, |x, y| image::Rgb([(x * 10) as u8, (y * 15) as u8, 90]));
+        let png = encode_image(&frame, ImageFormat::Png).unwrap();
+
+        let (decoded, format) = decode_image(&png).unwrap();
+        assert_eq!((decoded.as_raw(), format), (frame.as_raw(), ImageFormat::Png));
+
+        let gray = find("grayscale").unwrap().build(&HashMap::new()).unwrap().apply(&decoded).unwrap();
+        let jpeg = encode_image(&gray, image_format("jpg").unwrap()).unwrap();
+        let (decoded, format) = decode_image(&jpeg).unwrap();
+        assert_eq!((decoded.dimensions(), format), ((24, 16), ImageFormat::Jpeg));
+
+        assert!(image_format("gif").is_err());
+        assert!(decode_image(b"not an image").is_err());
+
+        let wide = encode_image(&RgbImage::new(helper::MAX_SIDE + 1, 1), ImageFormat::Png).unwrap();
+        assert!(decode_image(&wide).unwrap_err().to_string().contains("larger than 8192"));
+    }
+}
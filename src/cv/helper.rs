@@ -1,49 +1,420 @@
-use std::process::{Command, exit};
-use anyhow::Error;
+use std::process::Command;
+use anyhow::{anyhow, Error};
+use serde::Serialize;
+use std::collections::HashMap;
 use std::fs;
+use std::sync::OnceLock;
 
-pub fn to_pictures(video_path: &str) -> Result<(), Error>{
-    // ffmpeg -i output.mp4 -vf "fps=10,scale=426:240" output_frame_%04d.png
-    let output = Command::new("ffmpeg")
-        .args(&[
-            "-i", video_path,
-            "-vf", "fps=10,scale=426:240",
-            "./video/output_frame_%04d.png",
-        ])
-        .output()
-        .expect("Failed to execute command");
+// Rate and size frames are sampled at by the analysis endpoints, which only
+// report numbers and gain nothing from full resolution.
+pub const FRAME_RATE: f32 = 10.0;
+pub const ANALYSIS_SIZE: (u32, u32) = (426, 240);
 
-    // Check if the command executed successfully
-    if !output.status.success() {
-        eprintln!("Error: Command failed with status {}", output.status);
-        eprintln!("Standard Error: {}", String::from_utf8_lossy(&output.stderr));
-        exit(1);
+// A format the filtered frames can be written as. The name is also the
+// ffmpeg muxer and the file extension.
+#[derive(Debug, PartialEq)]
+pub struct Container {
+    pub name: &'static str,
+    pub content_type: &'static str,
+    // The first one is the default.
+    pub codecs: &'static [Codec],
+    // Audio codecs the container takes as they are and the one anything
+    // else is re-encoded to, `None` for formats without sound.
+    audio: Option<(&'static [&'static str], &'static str)>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Codec {
+    pub name: &'static str,
+    // Pixel formats it is used with, the first when the source's is not one.
+    pub pix_fmts: &'static [&'static str],
+    // Highest CRF, `None` when the codec has no constant quality mode.
+    pub max_crf: Option<u32>,
+    pub bitrate: bool,
+}
+
+const YUV: &[&str] = &["yuv420p", "yuv422p", "yuv444p"];
+
+pub const CONTAINERS: [Container; 4] = [
+    Container {
+        name: "mp4",
+        content_type: "video/mp4",
+        codecs: &[
+            Codec { name: "libx264", pix_fmts: YUV, max_crf: Some(51), bitrate: true },
+            Codec { name: "libx265", pix_fmts: YUV, max_crf: Some(51), bitrate: true },
+            Codec { name: "mpeg4", pix_fmts: &["yuv420p"], max_crf: None, bitrate: true },
+        ],
+        audio: Some((&["aac", "mp3", "ac3", "eac3", "alac"], "aac")),
+    },
+    Container {
+        name: "webm",
+        content_type: "video/webm",
+        codecs: &[
+            Codec { name: "libvpx-vp9", pix_fmts: YUV, max_crf: Some(63), bitrate: true },
+            Codec { name: "libvpx", pix_fmts: &["yuv420p"], max_crf: Some(63), bitrate: true },
+        ],
+        audio: Some((&["opus", "vorbis"], "libopus")),
+    },
+    // Animated images, without sound or a quality setting.
+    Container {
+        name: "gif",
+        content_type: "image/gif",
+        codecs: &[Codec { name: "gif", pix_fmts: &["pal8"], max_crf: None, bitrate: false }],
+        audio: None,
+    },
+    Container {
+        name: "apng",
+        content_type: "image/apng",
+        codecs: &[Codec { name: "apng", pix_fmts: &["rgb24"], max_crf: None, bitrate: false }],
+        audio: None,
+    },
+];
+
+impl Container {
+    // ffmpeg audio codec for a source track in `source_codec`: a copy when
+    // the container takes it, a re-encode otherwise, `None` when the
+    // container has no sound.
+    pub fn audio_codec(&self, source_codec: &str) -> Option<&'static str> {
+        let (copies, encoder) = self.audio?;
+        Some(if copies.contains(&source_codec) { "copy" } else { encoder })
     }
+}
 
-    Ok(())
+// Largest width or height frames are decoded at. One raw RGB frame at
+// that size is already 192 MB.
+pub const MAX_SIDE: u32 = 8192;
+
+// Size and rate frames are decoded at and how the filtered frames are
+// encoded. Anything left at `None` follows the source or the container's
+// default, a missing width or height keeps the aspect ratio.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VideoOptions {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fps: Option<f32>,
+    pub container: String,
+    pub codec: Option<String>,
+    // Constant quality, lower is better.
+    pub crf: Option<u32>,
+    // Target bitrate such as "2M" or "800k".
+    pub bitrate: Option<String>,
+    pub pix_fmt: Option<String>,
+    // Carry the source's audio track into the output.
+    pub audio: bool,
 }
 
-pub fn to_video() -> Result<(), Error>{
-    let output = Command::new("ffmpeg")
-        .args(&[
-            "-framerate", "10",
-            "-i", "./video/output_frame_%04d.png",
-            "-c:v", "libx264",
-            "-r", "30",
-            "-pix_fmt", "yuv420p",
-            "./video/output.mp4",
+impl Default for VideoOptions {
+    fn default() -> VideoOptions {
+        VideoOptions {
+            width: None,
+            height: None,
+            fps: None,
+            container: "mp4".to_string(),
+            codec: None,
+            crf: None,
+            bitrate: None,
+            pix_fmt: None,
+            audio: true,
+        }
+    }
+}
+
+impl VideoOptions {
+    // Fixed low resolution sampling for the analysis endpoints.
+    pub fn analysis() -> VideoOptions {
+        VideoOptions {
+            width: Some(ANALYSIS_SIZE.0),
+            height: Some(ANALYSIS_SIZE.1),
+            fps: Some(FRAME_RATE),
+            ..VideoOptions::default()
+        }
+    }
+
+    // Overrides the options with the ones given in a form: `width`,
+    // `height`, `fps`, `container`, `codec`, `crf`, `bitrate`, `pix_fmt` and
+    // `audio`.
+    pub fn with_params(mut self, params: &HashMap<String, String>) -> Result<VideoOptions, Error> {
+        if let Some(width) = params.get("width") {
+            self.width = Some(width.parse().map_err(|_| anyhow!("Invalid width '{}'", width))?);
+        }
+        if let Some(height) = params.get("height") {
+            self.height = Some(height.parse().map_err(|_| anyhow!("Invalid height '{}'", height))?);
+        }
+        if let Some(fps) = params.get("fps") {
+            self.fps = Some(fps.parse().map_err(|_| anyhow!("Invalid fps '{}'", fps))?);
+        }
+        if let Some(container) = params.get("container") {
+            // A codec chosen by a preset does not carry over to another
+            // container.
+            if *container != self.container {
+                self.codec = None;
+            }
+            self.container = container.clone();
+        }
+        if let Some(codec) = params.get("codec") {
+            self.codec = Some(codec.clone());
+        }
+        if let Some(crf) = params.get("crf") {
+            self.crf = Some(crf.parse().map_err(|_| anyhow!("Invalid crf '{}'", crf))?);
+        }
+        if let Some(bitrate) = params.get("bitrate") {
+            self.bitrate = Some(bitrate.clone());
+        }
+        if let Some(pix_fmt) = params.get("pix_fmt") {
+            self.pix_fmt = Some(pix_fmt.clone());
+        }
+        if let Some(audio) = params.get("audio") {
+            self.audio = audio.parse().map_err(|_| anyhow!("Invalid audio '{}', expected true or false", audio))?;
+        }
+        self.validate()?;
+        Ok(self)
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.width == Some(0) || self.height == Some(0) {
+            return Err(anyhow!("width and height must be positive"));
+        }
+        if self.width.is_some_and(|width| width > MAX_SIDE) || self.height.is_some_and(|height| height > MAX_SIDE) {
+            return Err(anyhow!("width and height must be at most {}", MAX_SIDE));
+        }
+        if let Some(fps) = self.fps {
+            if !(fps > 0.0 && fps <= 120.0) {
+                return Err(anyhow!("fps must be between 0 and 120, got {}", fps));
+            }
+        }
+
+        let codec = self.codec()?;
+        if let Some(crf) = self.crf {
+            match codec.max_crf {
+                Some(max_crf) if crf <= max_crf => {}
+                Some(max_crf) => return Err(anyhow!("crf must be between 0 and {} for {}", max_crf, codec.name)),
+                None => return Err(anyhow!("{} has no crf setting", codec.name)),
+            }
+        }
+        if let Some(bitrate) = &self.bitrate {
+            if !codec.bitrate {
+                return Err(anyhow!("{} has no bitrate setting", codec.name));
+            }
+            let digits = bitrate.strip_suffix(['k', 'M']).unwrap_or(bitrate);
+            if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+                return Err(anyhow!("Invalid bitrate '{}', expected a number of bits such as 800k or 2M", bitrate));
+            }
+        }
+        if let Some(pix_fmt) = &self.pix_fmt {
+            if !codec.pix_fmts.contains(&pix_fmt.as_str()) {
+                return Err(anyhow!(
+                    "{} can not encode {}, expected one of {}",
+                    codec.name, pix_fmt, codec.pix_fmts.join(", ")
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn container(&self) -> Result<&'static Container, Error> {
+        CONTAINERS.iter().find(|container| container.name == self.container).ok_or_else(|| {
+            let names: Vec<&str> = CONTAINERS.iter().map(|container| container.name).collect();
+            anyhow!("Unknown container '{}', expected one of {}", self.container, names.join(", "))
+        })
+    }
+
+    pub fn codec(&self) -> Result<&'static Codec, Error> {
+        let container = self.container()?;
+        match &self.codec {
+            Some(name) => container.codecs.iter().find(|codec| codec.name == name).ok_or_else(|| {
+                let names: Vec<&str> = container.codecs.iter().map(|codec| codec.name).collect();
+                anyhow!("Unknown codec '{}' for {}, expected one of {}", name, container.name, names.join(", "))
+            }),
+            None => Ok(&container.codecs[0]),
+        }
+    }
+
+    // Size frames are decoded at for a source of `source` pixels. A missing
+    // side follows the aspect ratio. Sides are rounded down to even, which
+    // yuv420p needs.
+    pub fn frame_size(&self, (source_width, source_height): (u32, u32)) -> (u32, u32) {
+        let scaled = |side: u32, from: u32, to: u32| (side as f64 * to as f64 / from.max(1) as f64).round() as u32;
+        let (width, height) = match (self.width, self.height) {
+            (Some(width), Some(height)) => (width, height),
+            (Some(width), None) => (width, scaled(source_height, source_width, width)),
+            (None, Some(height)) => (scaled(source_width, source_height, height), height),
+            (None, None) => (source_width, source_height),
+        };
+        let even = |side: u32| (side & !1).max(2);
+        (even(width), even(height))
+    }
+
+    // Rate frames are decoded at, the source's unless overridden. Sources
+    // that do not report one get the analysis rate.
+    pub fn frame_rate(&self, source_fps: f32) -> f32 {
+        match self.fps {
+            Some(fps) => fps,
+            None if source_fps > 0.0 => source_fps,
+            None => FRAME_RATE,
+        }
+    }
+
+    // Pixel format to encode with: the requested one, else the source's
+    // when the codec can write it, else the codec's default.
+    pub fn output_pix_fmt(&self, source_pix_fmt: &str) -> Result<&str, Error> {
+        let codec = self.codec()?;
+        Ok(match &self.pix_fmt {
+            Some(pix_fmt) => pix_fmt,
+            None => codec.pix_fmts.iter().find(|&&pix_fmt| pix_fmt == source_pix_fmt).unwrap_or(&codec.pix_fmts[0]),
+        })
+    }
+
+    // ffmpeg output options for the video stream and the container, placed
+    // after the inputs.
+    pub fn encoder_args(&self, source_pix_fmt: &str) -> Result<Vec<String>, Error> {
+        let container = self.container()?;
+        let codec = self.codec()?;
+
+        let mut args: Vec<String> = vec!["-c:v".into(), codec.name.into()];
+        if let Some(crf) = self.crf {
+            args.extend(["-crf".into(), crf.to_string()]);
+            // libvpx only keeps to the CRF when the bitrate is not capped.
+            if codec.name.starts_with("libvpx") && self.bitrate.is_none() {
+                args.extend(["-b:v".into(), "0".into()]);
+            }
+        }
+        if let Some(bitrate) = &self.bitrate {
+            args.extend(["-b:v".into(), bitrate.clone()]);
+        }
+        match container.name {
+            // One palette fitted to the whole clip looks far better than
+            // the fixed default one. FrameWriter generates it in a pass of
+            // its own and passes it as the second input.
+            "gif" => args.extend(["-lavfi".into(), "[0:v][1:v]paletteuse".into()]),
+            "apng" => args.extend(["-plays".into(), "0".into()]),
+            "mp4" => args.extend(["-movflags".into(), "+faststart".into()]),
+            _ => {}
+        }
+        args.extend([
+            "-pix_fmt".into(), self.output_pix_fmt(source_pix_fmt)?.into(),
+            "-f".into(), container.name.into(),
+        ]);
+        Ok(args)
+    }
+}
+
+// What ffprobe reports about the first video stream, and whether there is
+// audio to carry along.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Probe {
+    // Displayed size, with the rotation already applied the way ffmpeg
+    // applies it when decoding.
+    pub width: u32,
+    pub height: u32,
+    // Average frames per second, 0 when the stream does not say.
+    pub fps: f32,
+    // Seconds, 0 when the container does not say.
+    pub duration: f32,
+    // Clockwise degrees the player turns the stored frames, 0 to 270.
+    pub rotation: u32,
+    pub pix_fmt: String,
+    // Codec of the first audio stream, `None` for a silent video.
+    pub audio_codec: Option<String>,
+}
+
+impl Probe {
+    // Reads the output of `ffprobe -of json`.
+    pub fn from_json(info: &serde_json::Value) -> Result<Probe, Error> {
+        let streams = info["streams"].as_array().map(|streams| streams.as_slice()).unwrap_or_default();
+        let of_type = |codec_type: &str| streams.iter().find(|stream| stream["codec_type"] == codec_type);
+        let stream = of_type("video").ok_or_else(|| anyhow!("no video stream"))?;
+        let side = |key: &str| stream[key].as_u64().map(|side| side as u32).ok_or_else(|| anyhow!("ffprobe reported no {}", key));
+
+        // Rates are fractions such as "30000/1001", "0/0" when unknown.
+        let rate = |key: &str| {
+            let (num, den) = stream[key].as_str()?.split_once('/')?;
+            let (num, den): (f32, f32) = (num.parse().ok()?, den.parse().ok()?);
+            (num > 0.0 && den > 0.0).then(|| num / den)
+        };
+
+        // Newer files carry a display matrix, older ones a `rotate` tag.
+        // The matrix angle is counter-clockwise.
+        let side_data = stream["side_data_list"]
+            .as_array()
+            .and_then(|list| list.iter().find_map(|data| data["rotation"].as_f64()))
+            .map(|rotation| -rotation);
+        let tag = stream["tags"]["rotate"].as_str().and_then(|rotate| rotate.parse().ok());
+        let rotation = (side_data.or(tag).unwrap_or(0.0).round() as i64).rem_euclid(360) as u32;
+
+        let (width, height) = match rotation {
+            90 | 270 => (side("height")?, side("width")?),
+            _ => (side("width")?, side("height")?),
+        };
+
+        Ok(Probe {
+            width,
+            height,
+            fps: rate("avg_frame_rate").or_else(|| rate("r_frame_rate")).unwrap_or(0.0),
+            duration: info["format"]["duration"].as_str().and_then(|d| d.parse().ok()).unwrap_or(0.0),
+            rotation,
+            pix_fmt: stream["pix_fmt"].as_str().unwrap_or_default().to_string(),
+            audio_codec: of_type("audio").and_then(|audio| audio["codec_name"].as_str()).map(|codec| codec.to_string()),
+        })
+    }
+}
+
+// Size, rate, length, rotation and pixel format of the first video stream
+// and the codec of the first audio stream.
+pub fn probe(video_path: &str) -> Result<Probe, Error>{
+    let output = Command::new("ffprobe")
+        .args([
+            "-v", "error",
+            "-show_entries", "stream=codec_type,codec_name,width,height,avg_frame_rate,r_frame_rate,pix_fmt:stream_tags=rotate:stream_side_data=rotation:format=duration",
+            "-of", "json",
+            video_path,
         ])
-        .output()
-        .expect("Failed to execute command");
+        .output()?;
 
-    // Check if the command executed successfully
     if !output.status.success() {
-        eprintln!("Error: Command failed with status {}", output.status);
-        eprintln!("Standard Error: {}", String::from_utf8_lossy(&output.stderr));
-        exit(1);
+        return Err(anyhow!(
+            "ffprobe failed with status {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        ));
     }
 
-    Ok(())
+    let info: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    Probe::from_json(&info).map_err(|e| anyhow!("{}: {}", video_path, e))
+}
+
+// Upper bound for a requested worker count.
+pub const MAX_WORKERS: usize = 64;
+
+// Thread pool for per-frame work with one thread per core, built on first
+// use and shared by every job.
+pub fn thread_pool() -> &'static rayon::ThreadPool {
+    static POOL: OnceLock<rayon::ThreadPool> = OnceLock::new();
+    POOL.get_or_init(|| {
+        rayon::ThreadPoolBuilder::new()
+            .build()
+            .expect("Failed to start the frame thread pool")
+    })
+}
+
+// Frames a job hands to the shared pool at once, 0 workers uses one per
+// core. This bounds both the job's memory and its share of the pool.
+pub fn frames_in_flight(workers: usize) -> Result<usize, Error>{
+    if workers > MAX_WORKERS {
+        return Err(anyhow!("workers must be at most {}", MAX_WORKERS));
+    }
+    Ok(if workers == 0 { thread_pool().current_num_threads() } else { workers })
+}
+
+// Grey level of a threshold parameter: 0-255, or "otsu" (`None`) to pick
+// one for every frame.
+pub fn threshold_level(value: &str) -> Result<Option<u8>, Error>{
+    match value {
+        "otsu" => Ok(None),
+        level => level
+            .parse()
+            .map(Some)
+            .map_err(|_| anyhow!("Invalid threshold '{}', expected 0-255 or otsu", level)),
+    }
 }
 
 pub fn get_all_files_in_folder(folder_path: &str) -> Result<Vec<String>, Error>{
@@ -61,6 +432,150 @@ pub fn get_all_files_in_folder(folder_path: &str) -> Result<Vec<String>, Error>{
             }
         }
     }
+
+    // read_dir gives no ordering guarantee, keep frames in sequence.
+    files.sort();
     
     Ok(files)
-}
\ No newline at end of file
+}
+
+pub fn run_ffmpeg(args: &[&str]) -> Result<(), Error>{
+    let output = Command::new("ffmpeg")
+        .args(args)
+        .output()?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "ffmpeg failed with status {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    Ok(())
+}
+
+pub fn cut_clip(video_path: &str, start: f32, end: f32, output_path: &str) -> Result<(), Error>{
+    // Re-encode instead of stream copying so the cut lands on the exact frame
+    // rather than the nearest keyframe. The audio is cut by the same input
+    // seek, so it stays in sync, and re-encoded for the same reason.
+    run_ffmpeg(&[
+        "-y",
+        "-ss", &format!("{:.3}", start),
+        "-to", &format!("{:.3}", end),
+        "-i", video_path,
+        "-c:v", "libx264",
+        "-pix_fmt", "yuv420p",
+        "-c:a", "aac",
+        output_path,
+    ])
+}
+
+pub fn concat_clips(clip_paths: &[String], output_path: &str) -> Result<(), Error>{
+    let list_path = format!("{}.txt", output_path);
+    let list = clip_paths
+        .iter()
+        .map(|path| format!("file '{}'\n", fs::canonicalize(path).unwrap_or(path.into()).display()))
+        .collect::<String>();
+    fs::write(&list_path, list)?;
+
+    let result = run_ffmpeg(&[
+        "-y",
+        "-f", "concat",
+        "-safe", "0",
+        "-i", &list_path,
+        "-c", "copy",
+        output_path,
+    ]);
+
+    fs::remove_file(&list_path)?;
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_probe_reads_rate_and_rotation() {
+        let info = serde_json::json!({
+            "streams": [{
+                "codec_type": "video",
+                "width": 1920,
+                "height": 1080,
+                "pix_fmt": "yuv420p",
+                "r_frame_rate": "30/1",
+                "avg_frame_rate": "30000/1001",
+                "side_data_list": [{ "rotation": -90 }]
+            }, {
+                "codec_type": "audio",
+                "codec_name": "opus"
+            }],
+            "format": { "duration": "12.5" }
+        });
+        let probe = Probe::from_json(&info).unwrap();
+        assert_eq!((probe.width, probe.height, probe.rotation), (1080, 1920, 90));
+        assert!((probe.fps - 29.97).abs() < 0.01);
+        assert_eq!((probe.duration, probe.pix_fmt.as_str()), (12.5, "yuv420p"));
+        assert_eq!(probe.audio_codec.as_deref(), Some("opus"));
+
+        let tagged = serde_json::json!({
+            "streams": [{ "codec_type": "video", "width": 640, "height": 360, "avg_frame_rate": "0/0", "tags": { "rotate": "180" } }],
+            "format": {}
+        });
+        let probe = Probe::from_json(&tagged).unwrap();
+        assert_eq!((probe.width, probe.height, probe.rotation, probe.fps, probe.duration), (640, 360, 180, 0.0, 0.0));
+        assert_eq!(probe.audio_codec, None);
+    }
+
+    #[test]
+    pub fn test_output_follows_the_source_unless_overridden() {
+        let video = VideoOptions::default();
+        assert_eq!(video.frame_size((1280, 720)), (1280, 720));
+        assert_eq!(video.frame_rate(24.0), 24.0);
+        assert_eq!(video.output_pix_fmt("yuv444p").unwrap(), "yuv444p");
+        assert_eq!(video.output_pix_fmt("yuvj420p").unwrap(), "yuv420p");
+
+        let params = [("width", "640"), ("fps", "12.5"), ("audio", "false")]
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        let video = VideoOptions::default().with_params(&params).unwrap();
+        assert_eq!(video.frame_size((1280, 720)), (640, 360));
+        assert_eq!(video.frame_rate(24.0), 12.5);
+        assert!(!video.audio);
+
+        let params = [("fps".to_string(), "0".to_string())].into_iter().collect();
+        assert!(VideoOptions::default().with_params(&params).is_err());
+    }
+    #[test]
+    pub fn test_containers_pick_codec_quality_and_audio() {
+        let options = |params: &[(&str, &str)]| {
+            let params = params.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+            VideoOptions::default().with_params(&params)
+        };
+
+        let webm = options(&[("container", "webm"), ("crf", "31")]).unwrap();
+        assert_eq!(
+            webm.encoder_args("yuv420p").unwrap().join(" "),
+            "-c:v libvpx-vp9 -crf 31 -b:v 0 -pix_fmt yuv420p -f webm"
+        );
+        assert_eq!(webm.container().unwrap().audio_codec("opus"), Some("copy"));
+        assert_eq!(webm.container().unwrap().audio_codec("aac"), Some("libopus"));
+
+        let gif = options(&[("container", "gif")]).unwrap();
+        assert!(gif.encoder_args("yuv420p").unwrap().join(" ").contains("paletteuse"));
+        assert_eq!(gif.container().unwrap().audio_codec("aac"), None);
+        assert_eq!(gif.container().unwrap().content_type, "image/gif");
+
+        let error = |params: &[(&str, &str)]| options(params).err().unwrap().to_string();
+        assert!(error(&[("container", "avi")]).contains("Unknown container 'avi'"));
+        assert!(error(&[("codec", "libvpx-vp9")]).contains("Unknown codec 'libvpx-vp9' for mp4"));
+        assert!(error(&[("width", "8193")]).contains("at most 8192"));
+        assert!(error(&[("crf", "60")]).contains("between 0 and 51"));
+        assert!(error(&[("container", "apng"), ("crf", "20")]).contains("no crf setting"));
+        assert!(error(&[("bitrate", "fast")]).contains("Invalid bitrate"));
+        assert!(error(&[("codec", "mpeg4"), ("pix_fmt", "yuv444p")]).contains("can not encode"));
+    }
+}
@@ -0,0 +1,418 @@
+use crate::cv::{vision, helper, draw};
+use crate::cv::filter::{Filter, Param};
+use anyhow::{anyhow, Error};
+use image::{DynamicImage, GrayImage, Rgb, RgbImage};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::f32::consts::PI;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HoughMethod {
+    Standard,
+    Probabilistic,
+}
+
+#[derive(Debug, Clone)]
+pub struct HoughOptions {
+    pub method: HoughMethod,
+    // Accumulator resolution, rho in pixels and theta in degrees.
+    pub rho: f32,
+    pub theta: f32,
+    // Minimum number of votes for a line.
+    pub threshold: u32,
+    // Probabilistic only: shortest segment kept and the largest hole bridged
+    // between edge pixels of the same segment.
+    pub min_length: f32,
+    pub max_gap: f32,
+    // Level the Sobel magnitude is binarized at, `None` picks it with Otsu.
+    pub edge_threshold: Option<u8>,
+    // 0 keeps every line.
+    pub max_lines: usize,
+}
+
+impl Default for HoughOptions {
+    fn default() -> HoughOptions {
+        HoughOptions {
+            method: HoughMethod::Probabilistic,
+            rho: 1.0,
+            theta: 1.0,
+            threshold: 50,
+            min_length: 30.0,
+            max_gap: 10.0,
+            edge_threshold: None,
+            max_lines: 50,
+        }
+    }
+}
+
+impl HoughOptions {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<HoughOptions, Error> {
+        let mut options = HoughOptions::default();
+
+        if let Some(method) = params.get("method") {
+            options.method = match method.as_str() {
+                "standard" => HoughMethod::Standard,
+                "probabilistic" => HoughMethod::Probabilistic,
+                other => return Err(anyhow!("Unknown Hough method '{}'", other)),
+            };
+        }
+        if let Some(rho) = params.get("rho") {
+            options.rho = rho.parse()?;
+            if options.rho <= 0.0 {
+                return Err(anyhow!("rho must be positive"));
+            }
+        }
+        if let Some(theta) = params.get("theta") {
+            options.theta = theta.parse()?;
+            if options.theta <= 0.0 || options.theta > 90.0 {
+                return Err(anyhow!("theta must be between 0 and 90 degrees"));
+            }
+        }
+        if let Some(threshold) = params.get("threshold") {
+            options.threshold = threshold.parse()?;
+        }
+        if let Some(min_length) = params.get("min_length") {
+            options.min_length = min_length.parse()?;
+        }
+        if let Some(max_gap) = params.get("max_gap") {
+            options.max_gap = max_gap.parse()?;
+        }
+        if let Some(edge_threshold) = params.get("edge_threshold") {
+            options.edge_threshold = helper::threshold_level(edge_threshold)?;
+        }
+        if let Some(max_lines) = params.get("max_lines") {
+            options.max_lines = max_lines.parse()?;
+        }
+
+        Ok(options)
+    }
+
+    fn thetas(&self) -> Vec<(f32, f32)> {
+        let count = (180.0 / self.theta).round().max(1.0) as usize;
+        (0..count)
+            .map(|i| {
+                let angle = i as f32 * PI / count as f32;
+                (angle.cos(), angle.sin())
+            })
+            .collect()
+    }
+}
+
+// Infinite line in normal form: x cos(theta) + y sin(theta) = rho.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Line {
+    pub rho: f32,
+    pub theta: f32,
+    pub votes: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Segment {
+    pub start: (u32, u32),
+    pub end: (u32, u32),
+}
+
+impl Segment {
+    pub fn length(&self) -> f32 {
+        let dx = self.end.0 as f32 - self.start.0 as f32;
+        let dy = self.end.1 as f32 - self.start.1 as f32;
+        (dx * dx + dy * dy).sqrt()
+    }
+}
+
+// Binary edge map from the Sobel magnitude.
+pub fn edge_map(gray: &GrayImage, edge_threshold: Option<u8>) -> Result<GrayImage, Error> {
+    let magnitude = vision::CompVision::edge_detection_sobel(DynamicImage::ImageLuma8(gray.clone()))?;
+    vision::CompVision::binarize(DynamicImage::ImageLuma8(magnitude), edge_threshold, false)
+}
+
+fn edge_points(edges: &GrayImage) -> Vec<(u32, u32)> {
+    edges
+        .enumerate_pixels()
+        .filter(|(_, _, pixel)| pixel.0[0] > 0)
+        .map(|(x, y, _)| (x, y))
+        .collect()
+}
+
+struct Accumulator {
+    votes: Vec<i32>,
+    thetas: Vec<(f32, f32)>,
+    bins: usize,
+    offset: f32,
+    rho: f32,
+}
+
+impl Accumulator {
+    fn new(width: u32, height: u32, options: &HoughOptions) -> Accumulator {
+        let diagonal = (width as f32).hypot(height as f32);
+        // Whole number of bins on either side so rho = 0 falls on a bin centre.
+        let half = (diagonal / options.rho).ceil();
+        let bins = 2 * half as usize + 1;
+        let thetas = options.thetas();
+        Accumulator { votes: vec![0; bins * thetas.len()], thetas, bins, offset: half * options.rho, rho: options.rho }
+    }
+
+    fn bin(&self, (x, y): (u32, u32), (cos, sin): (f32, f32)) -> usize {
+        let r = x as f32 * cos + y as f32 * sin;
+        ((r + self.offset) / self.rho).round() as usize
+    }
+
+    // Adds (or with -1 removes) the votes of one edge pixel and returns the
+    // strongest cell it touched.
+    fn vote(&mut self, point: (u32, u32), weight: i32) -> (usize, i32) {
+        let mut best = (0, i32::MIN);
+        for t in 0..self.thetas.len() {
+            let index = t * self.bins + self.bin(point, self.thetas[t]);
+            self.votes[index] += weight;
+            if self.votes[index] > best.1 {
+                best = (index, self.votes[index]);
+            }
+        }
+        best
+    }
+
+    fn line(&self, index: usize) -> (f32, usize) {
+        let (t, r) = (index / self.bins, index % self.bins);
+        (r as f32 * self.rho - self.offset, t)
+    }
+}
+
+// Standard Hough transform: every edge pixel votes for all lines through it,
+// lines are the local maxima of the accumulator above the threshold.
+pub fn hough_lines(edges: &GrayImage, options: &HoughOptions) -> Vec<Line> {
+    let mut accumulator = Accumulator::new(edges.width(), edges.height(), options);
+    for point in edge_points(edges) {
+        accumulator.vote(point, 1);
+    }
+
+    let (bins, count) = (accumulator.bins, accumulator.thetas.len());
+    let at = |t: usize, r: usize| accumulator.votes[t * bins + r];
+    let mut lines = Vec::new();
+    for t in 0..count {
+        for r in 0..bins {
+            let votes = at(t, r);
+            if votes < options.threshold as i32 || votes == 0 {
+                continue;
+            }
+            // 3x3 neighbourhood, theta wraps around at 180 degrees where
+            // the same line shows up again with its rho negated.
+            let is_peak = (-1..=1i32)
+                .flat_map(|dt| (-1..=1i32).map(move |dr| (dt, dr)))
+                .filter(|&d| d != (0, 0))
+                .filter_map(|(dt, dr)| {
+                    let nr = r as i32 + dr;
+                    let (nt, nr) = match t as i32 + dt {
+                        -1 => (count - 1, bins as i32 - 1 - nr),
+                        nt if nt == count as i32 => (0, bins as i32 - 1 - nr),
+                        nt => (nt as usize, nr),
+                    };
+                    (0..bins as i32).contains(&nr).then_some((nt, nr as usize))
+                })
+                .all(|(nt, nr)| at(nt, nr) < votes || (at(nt, nr) == votes && (nt, nr) > (t, r)));
+            if is_peak {
+                let (rho, _) = accumulator.line(t * bins + r);
+                lines.push(Line { rho, theta: t as f32 * PI / count as f32, votes: votes as u32 });
+            }
+        }
+    }
+
+    lines.sort_by_key(|line| std::cmp::Reverse(line.votes));
+    if options.max_lines > 0 {
+        lines.truncate(options.max_lines);
+    }
+    lines
+}
+
+// Progressive probabilistic Hough transform (Matas, Galambos & Kittler).
+// Edge pixels vote in random order; as soon as a cell crosses the threshold
+// the corresponding line is walked in the edge map to find the segment, whose
+// pixels are then removed so they cannot vote for anything else.
+pub fn probabilistic_hough(edges: &GrayImage, options: &HoughOptions) -> Vec<Segment> {
+    let (width, height) = edges.dimensions();
+    let mut accumulator = Accumulator::new(width, height, options);
+
+    let mut points = edge_points(edges);
+    points.shuffle(&mut StdRng::seed_from_u64(0x4011));
+
+    let index = |(x, y): (u32, u32)| (y * width + x) as usize;
+    let mut mask: Vec<bool> = edges.pixels().map(|pixel| pixel.0[0] > 0).collect();
+    let mut voted = vec![false; mask.len()];
+    let mut segments = Vec::new();
+
+    for point in points {
+        if options.max_lines > 0 && segments.len() >= options.max_lines {
+            break;
+        }
+        if !mask[index(point)] {
+            continue;
+        }
+
+        voted[index(point)] = true;
+        let (cell, votes) = accumulator.vote(point, 1);
+        if votes < options.threshold as i32 {
+            continue;
+        }
+
+        // Walk along the line direction, which is perpendicular to its normal.
+        let (_, t) = accumulator.line(cell);
+        let (cos, sin) = accumulator.thetas[t];
+        let major = cos.abs().max(sin.abs());
+        let step = (-sin / major, cos / major);
+
+        let inside = |x: f32, y: f32| x >= 0.0 && y >= 0.0 && x < width as f32 && y < height as f32;
+        let mut ends = [point; 2];
+        for (k, end) in ends.iter_mut().enumerate() {
+            let sign = if k == 0 { -1.0 } else { 1.0 };
+            let (mut x, mut y) = (point.0 as f32, point.1 as f32);
+            let mut gap = 0.0;
+            loop {
+                x += sign * step.0;
+                y += sign * step.1;
+                let (px, py) = (x.round(), y.round());
+                if !inside(px, py) {
+                    break;
+                }
+                if mask[index((px as u32, py as u32))] {
+                    gap = 0.0;
+                    *end = (px as u32, py as u32);
+                } else {
+                    gap += 1.0;
+                    if gap > options.max_gap {
+                        break;
+                    }
+                }
+            }
+        }
+
+        let segment = Segment { start: ends[0], end: ends[1] };
+        let good = segment.length() >= options.min_length;
+
+        // Sobel edges are a few pixels wide, so the pixels right next to the
+        // segment are consumed with it to avoid reporting parallel copies.
+        let steps = segment.length().ceil() as i32;
+        let (dx, dy) = (
+            (ends[1].0 as f32 - ends[0].0 as f32) / steps.max(1) as f32,
+            (ends[1].1 as f32 - ends[0].1 as f32) / steps.max(1) as f32,
+        );
+        for s in 0..=steps {
+            let (x, y) = (ends[0].0 as f32 + dx * s as f32, ends[0].1 as f32 + dy * s as f32);
+            for n in -1..=1 {
+                let (px, py) = ((x + n as f32 * cos).round(), (y + n as f32 * sin).round());
+                if !inside(px, py) {
+                    continue;
+                }
+                let p = (px as u32, py as u32);
+                if mask[index(p)] {
+                    if good && voted[index(p)] {
+                        accumulator.vote(p, -1);
+                    }
+                    mask[index(p)] = false;
+                }
+            }
+        }
+
+        if good {
+            segments.push(segment);
+        }
+    }
+
+    segments
+}
+
+pub fn draw_hough(frame: &RgbImage, options: &HoughOptions) -> Result<RgbImage, Error> {
+    let gray = vision::CompVision::to_grayscale(DynamicImage::ImageRgb8(frame.clone()))?;
+    let edges = edge_map(&gray, options.edge_threshold)?;
+
+    let mut test_img = frame.clone();
+    let color = Rgb([255, 0, 0]);
+    match options.method {
+        HoughMethod::Standard => {
+            let reach = (frame.width() + frame.height()) as f32;
+            for line in hough_lines(&edges, options) {
+                let (cos, sin) = (line.theta.cos(), line.theta.sin());
+                let (x0, y0) = (line.rho * cos, line.rho * sin);
+                let from = (x0 - reach * sin, y0 + reach * cos);
+                let to = (x0 + reach * sin, y0 - reach * cos);
+                draw::draw_line(&mut test_img, from, to, color, 2);
+            }
+        }
+        HoughMethod::Probabilistic => {
+            for segment in probabilistic_hough(&edges, options) {
+                let from = (segment.start.0 as f32, segment.start.1 as f32);
+                let to = (segment.end.0 as f32, segment.end.1 as f32);
+                draw::draw_line(&mut test_img, from, to, color, 2);
+            }
+        }
+    }
+
+    Ok(test_img)
+}
+
+impl Filter for HoughOptions {
+    fn name() -> &'static str { "hough" }
+    fn label() -> &'static str { "Hough Lines" }
+
+    fn params() -> Vec<Param> {
+        let defaults = HoughOptions::default();
+        vec![
+            Param::choice("method", &["standard", "probabilistic"], "probabilistic", "Infinite lines or segments"),
+            Param::float("rho", defaults.rho, "Distance resolution in pixels"),
+            Param::float("theta", defaults.theta, "Angle resolution in degrees"),
+            Param::int("threshold", defaults.threshold, "Votes needed for a line"),
+            Param::float("min_length", defaults.min_length, "Shortest segment kept"),
+            Param::float("max_gap", defaults.max_gap, "Largest gap bridged within a segment"),
+            Param::text("edge_threshold", "otsu", "Sobel magnitude level, or otsu"),
+            Param::int("max_lines", defaults.max_lines, "Most lines per frame, 0 for all"),
+        ]
+    }
+
+    fn from_params(params: &HashMap<String, String>) -> Result<HoughOptions, Error> {
+        HoughOptions::from_params(params)
+    }
+
+    fn apply(&mut self, frame: &RgbImage) -> Result<RgbImage, Error> {
+        draw_hough(frame, self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn two_lines() -> GrayImage {
+        GrayImage::from_fn(100, 60, |x, y| {
+            let horizontal = y == 30 && (10..90).contains(&x);
+            let vertical = x == 70 && (5..55).contains(&y);
+            image::Luma([if horizontal || vertical { 255 } else { 0 }])
+        })
+    }
+
+    #[test]
+    pub fn test_standard_hough_finds_both_lines() {
+        let options = HoughOptions { method: HoughMethod::Standard, threshold: 40, ..HoughOptions::default() };
+        let lines = hough_lines(&two_lines(), &options);
+
+        assert_eq!(lines.len(), 2, "{:?}", lines);
+        let near = |line: &Line, rho: f32, degrees: f32| {
+            (line.rho - rho).abs() <= 1.0 && (line.theta.to_degrees() - degrees).abs() <= 1.0
+        };
+        assert!(lines.iter().any(|l| near(l, 30.0, 90.0)), "{:?}", lines);
+        assert!(lines.iter().any(|l| near(l, 70.0, 0.0)), "{:?}", lines);
+    }
+
+    #[test]
+    pub fn test_probabilistic_hough_returns_segments() {
+        let options = HoughOptions { threshold: 20, ..HoughOptions::default() };
+        let mut segments = probabilistic_hough(&two_lines(), &options);
+        segments.iter_mut().for_each(|s| if s.start > s.end { std::mem::swap(&mut s.start, &mut s.end) });
+        segments.sort_by_key(|s| s.start);
+
+        assert_eq!(segments.len(), 2, "{:?}", segments);
+        let close = |a: (u32, u32), b: (u32, u32)| a.0.abs_diff(b.0) <= 1 && a.1.abs_diff(b.1) <= 1;
+        assert!(close(segments[0].start, (10, 30)) && close(segments[0].end, (89, 30)), "{:?}", segments);
+        assert!(close(segments[1].start, (70, 5)) && close(segments[1].end, (70, 54)), "{:?}", segments);
+    }
+}
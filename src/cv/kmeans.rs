@@ -1,42 +1,76 @@
-use crate::cv::{vision, helper};
-use anyhow::Error;
-use std::fs;
-use indicatif::{ProgressBar, ProgressStyle};
+use crate::cv::filter::{Filter, Param};
+use anyhow::{anyhow, Error};
+use image::{Rgb, RgbImage};
+use std::collections::HashMap;
 
-pub fn kmeans_filter(video_path: &str) -> Result<(), Error>{
-    helper::to_pictures(video_path)?;
+// Lloyd's k-means on the frame colours, seeded from evenly spaced pixels so
+// consecutive frames get a stable palette.
+pub fn quantize(frame: &RgbImage, k: usize) -> RgbImage {
+    const ITERATIONS: usize = 8;
+    // Clustering a subsample is plenty to find the palette.
+    const SAMPLES: usize = 4096;
 
-    println!("Fetching pics from {} ..", video_path);
+    let pixels: Vec<[f32; 3]> = frame.pixels().map(|p| p.0.map(|v| v as f32)).collect();
+    let stride = (pixels.len() / SAMPLES).max(1);
+    let samples: Vec<[f32; 3]> = pixels.iter().step_by(stride).cloned().collect();
 
-    let pictures = helper::get_all_files_in_folder("./video")?;
+    let nearest = |centers: &[[f32; 3]], color: &[f32; 3]| {
+        (0..centers.len())
+            .min_by(|&a, &b| {
+                let d = |c: &[f32; 3]| c.iter().zip(color).map(|(p, q)| (p - q) * (p - q)).sum::<f32>();
+                d(&centers[a]).total_cmp(&d(&centers[b]))
+            })
+            .unwrap_or(0)
+    };
 
-    let total = pictures.len() as u64;
-    let pb = ProgressBar::new(total);
-    pb.set_style(ProgressStyle::default_bar()
-        .template("{msg} {bar:40.cyan/blue} {percent:>3}%")?
-        .progress_chars("█▌▐"));
-
-
-    for pic in &pictures{
-        if pic.contains("png"){
-            let image_path = format!("./video/{}", pic);
-            let cv = vision::CompVision::new(&image_path)?;
-            let test_img = vision::CompVision::kmeans(cv.image, 10).unwrap();
-            test_img.save(image_path)?; 
-            pb.inc(1);
+    let mut centers: Vec<[f32; 3]> = (0..k).map(|i| samples[i * samples.len() / k]).collect();
+    for _ in 0..ITERATIONS {
+        let mut sums = vec![[0f32; 4]; k];
+        for color in &samples {
+            let sum = &mut sums[nearest(&centers, color)];
+            for (total, value) in sum.iter_mut().zip(color) {
+                *total += value;
+            }
+            sum[3] += 1.0;
+        }
+        for (center, sum) in centers.iter_mut().zip(&sums) {
+            if sum[3] > 0.0 {
+                *center = [sum[0] / sum[3], sum[1] / sum[3], sum[2] / sum[3]];
+            }
         }
     }
 
-    println!("Building video ..");
+    let mut out = RgbImage::new(frame.width(), frame.height());
+    for (pixel, color) in out.pixels_mut().zip(&pixels) {
+        *pixel = Rgb(centers[nearest(&centers, color)].map(|v| v.round() as u8));
+    }
+    out
+}
+
+pub struct KMeans {
+    pub colors: usize,
+}
 
-    helper::to_video()?;
+impl Filter for KMeans {
+    fn name() -> &'static str { "kmeans" }
+    fn label() -> &'static str { "K-Means" }
 
-    for pic in pictures{
-        if pic.contains("png"){
-            let image_path = format!("./video/{}", pic);
-            fs::remove_file(image_path)?;
+    fn params() -> Vec<Param> {
+        vec![Param::int("colors", 10, "Size of the palette")]
+    }
+
+    fn from_params(params: &HashMap<String, String>) -> Result<KMeans, Error> {
+        let colors = match params.get("colors") {
+            Some(colors) => colors.parse()?,
+            None => 10,
+        };
+        if !(2..=64).contains(&colors) {
+            return Err(anyhow!("colors must be between 2 and 64"));
         }
+        Ok(KMeans { colors })
     }
 
-    Ok(())
+    fn apply(&mut self, frame: &RgbImage) -> Result<RgbImage, Error> {
+        Ok(quantize(frame, self.colors))
+    }
 }
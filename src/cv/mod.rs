@@ -1,6 +1,24 @@
-pub mod sobel;
 pub mod vision;
 pub mod helper;
-pub mod posterize;
-pub mod grayscale;
-pub mod kmeans;
\ No newline at end of file
+pub mod kmeans;
+pub mod background;
+pub mod motion;
+pub mod clips;
+pub mod scenes;
+pub mod stabilize;
+pub mod draw;
+pub mod tracker;
+pub mod contours;
+pub mod components;
+pub mod corners;
+pub mod features;
+pub mod panorama;
+pub mod hough;
+pub mod circles;
+pub mod watershed;
+pub mod slic;
+pub mod cartoon;
+pub mod filter;
+pub mod pipeline;
+pub mod preset;
+pub mod stream;
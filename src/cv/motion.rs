@@ -0,0 +1,140 @@
+use crate::cv::background::{self, BackgroundModel, MixtureOfGaussians, RunningAverage};
+use crate::cv::filter::{Filter, Param};
+use anyhow::{anyhow, Error};
+use image::{DynamicImage, ImageBuffer, Rgb, RgbImage};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Model {
+    RunningAverage,
+    MixtureOfGaussians,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Output {
+    Mask,
+    Highlight,
+}
+
+#[derive(Debug, Clone)]
+pub struct MotionOptions {
+    pub model: Model,
+    pub learning_rate: f32,
+    pub threshold: f32,
+    pub detect_shadows: bool,
+    pub output: Output,
+}
+
+impl Default for MotionOptions {
+    fn default() -> MotionOptions {
+        MotionOptions {
+            model: Model::MixtureOfGaussians,
+            learning_rate: 0.01,
+            threshold: 40.0,
+            detect_shadows: true,
+            output: Output::Highlight,
+        }
+    }
+}
+
+impl MotionOptions {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<MotionOptions, Error> {
+        let mut options = MotionOptions::default();
+
+        if let Some(model) = params.get("model") {
+            options.model = match model.as_str() {
+                "average" => Model::RunningAverage,
+                "mog" => Model::MixtureOfGaussians,
+                other => return Err(anyhow!("Unknown background model '{}'", other)),
+            };
+        }
+        if let Some(rate) = params.get("learning_rate") {
+            options.learning_rate = rate.parse()?;
+            if !(0.0..=1.0).contains(&options.learning_rate) {
+                return Err(anyhow!("learning_rate must be between 0 and 1"));
+            }
+        }
+        if let Some(threshold) = params.get("threshold") {
+            options.threshold = threshold.parse()?;
+        }
+        if let Some(shadows) = params.get("shadows") {
+            options.detect_shadows = shadows.parse()?;
+        }
+        if let Some(output) = params.get("output") {
+            options.output = match output.as_str() {
+                "mask" => Output::Mask,
+                "highlight" => Output::Highlight,
+                other => return Err(anyhow!("Unknown motion output '{}'", other)),
+            };
+        }
+
+        Ok(options)
+    }
+
+    pub fn build_model(&self) -> Box<dyn BackgroundModel> {
+        match self.model {
+            Model::RunningAverage => Box::new(RunningAverage::new(self.learning_rate, self.threshold, self.detect_shadows)),
+            Model::MixtureOfGaussians => Box::new(MixtureOfGaussians::new(self.learning_rate, self.detect_shadows)),
+        }
+    }
+}
+
+// Tints moving regions red on top of the original frame, shadows are left untouched.
+pub fn highlight(frame: &RgbImage, mask: &background::Mask) -> RgbImage {
+    let (width, height) = frame.dimensions();
+    let mut test_img = ImageBuffer::new(width, height);
+
+    for (x, y, pixel) in frame.enumerate_pixels() {
+        let rgb = if mask.get_pixel(x, y).0[0] == background::FOREGROUND {
+            Rgb([
+                ((pixel.0[0] as u16 + 255) / 2) as u8,
+                pixel.0[1] / 2,
+                pixel.0[2] / 2,
+            ])
+        } else {
+            *pixel
+        };
+        test_img.put_pixel(x, y, rgb);
+    }
+
+    test_img
+}
+
+// Motion as a video filter, the background model lives for the whole job.
+pub struct MotionFilter {
+    options: MotionOptions,
+    model: Box<dyn BackgroundModel>,
+}
+
+impl Filter for MotionFilter {
+    fn name() -> &'static str { "motion" }
+    fn label() -> &'static str { "Motion" }
+
+    fn params() -> Vec<Param> {
+        let defaults = MotionOptions::default();
+        vec![
+            Param::choice("model", &["mog", "average"], "mog", "Background model"),
+            Param::float("learning_rate", defaults.learning_rate, "How fast the background adapts"),
+            Param::float("threshold", defaults.threshold, "Running average foreground distance"),
+            Param::bool("shadows", defaults.detect_shadows, "Keep shadows out of the foreground"),
+            Param::choice("output", &["highlight", "mask"], "highlight", "Tinted frame or the raw mask"),
+        ]
+    }
+
+    fn from_params(params: &HashMap<String, String>) -> Result<MotionFilter, Error> {
+        let options = MotionOptions::from_params(params)?;
+        let model = options.build_model();
+        Ok(MotionFilter { options, model })
+    }
+
+    // The background model learns from every frame.
+    fn stateful(&self) -> bool { true }
+
+    fn apply(&mut self, frame: &RgbImage) -> Result<RgbImage, Error> {
+        let mask = self.model.apply(frame);
+        Ok(match self.options.output {
+            Output::Mask => DynamicImage::ImageLuma8(mask).to_rgb8(),
+            Output::Highlight => highlight(frame, &mask),
+        })
+    }
+}
@@ -0,0 +1,334 @@
+use crate::cv::{vision, features};
+use crate::cv::features::OrbOptions;
+use anyhow::{anyhow, Error};
+use image::{ImageBuffer, Rgb, RgbImage};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+
+pub type Homography = [[f64; 3]; 3];
+
+// A matched point in the source image and where it lands in the target.
+pub type Correspondence = ((f64, f64), (f64, f64));
+
+#[derive(Debug, Clone)]
+pub struct PanoramaOptions {
+    pub orb: OrbOptions,
+    // Reprojection error in pixels below which a match counts as an inlier.
+    pub ransac_threshold: f64,
+    pub iterations: usize,
+    // Neighbouring images with fewer inliers are treated as not overlapping.
+    pub min_inliers: usize,
+}
+
+impl Default for PanoramaOptions {
+    fn default() -> PanoramaOptions {
+        PanoramaOptions {
+            orb: OrbOptions { features: 1000, ..OrbOptions::default() },
+            ransac_threshold: 3.0,
+            iterations: 1000,
+            min_inliers: 12,
+        }
+    }
+}
+
+impl PanoramaOptions {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<PanoramaOptions, Error> {
+        let mut options = PanoramaOptions::default();
+        let features = options.orb.features;
+        options.orb = OrbOptions::from_params(params)?;
+        if !params.contains_key("features") {
+            options.orb.features = features;
+        }
+
+        if let Some(ransac_threshold) = params.get("ransac_threshold") {
+            options.ransac_threshold = ransac_threshold.parse()?;
+        }
+        if let Some(iterations) = params.get("iterations") {
+            options.iterations = iterations.parse()?;
+        }
+        if let Some(min_inliers) = params.get("min_inliers") {
+            options.min_inliers = min_inliers.parse()?;
+            if options.min_inliers < 4 {
+                return Err(anyhow!("min_inliers must be at least 4"));
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+const IDENTITY: Homography = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+// Canvases above this many pixels usually mean a degenerate homography.
+const MAX_CANVAS_PIXELS: f64 = 50_000_000.0;
+
+fn multiply(a: &Homography, b: &Homography) -> Homography {
+    let mut out = [[0.0; 3]; 3];
+    for (i, row) in out.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    out
+}
+
+fn invert(m: &Homography) -> Option<Homography> {
+    let cofactor = |r0: usize, r1: usize, c0: usize, c1: usize| m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0];
+    let adjugate = [
+        [cofactor(1, 2, 1, 2), -cofactor(0, 2, 1, 2), cofactor(0, 1, 1, 2)],
+        [-cofactor(1, 2, 0, 2), cofactor(0, 2, 0, 2), -cofactor(0, 1, 0, 2)],
+        [cofactor(1, 2, 0, 1), -cofactor(0, 2, 0, 1), cofactor(0, 1, 0, 1)],
+    ];
+    let det = m[0][0] * adjugate[0][0] + m[0][1] * adjugate[1][0] + m[0][2] * adjugate[2][0];
+    if det.abs() < 1e-12 {
+        return None;
+    }
+    Some(adjugate.map(|row| row.map(|value| value / det)))
+}
+
+pub fn project(h: &Homography, (x, y): (f64, f64)) -> (f64, f64) {
+    let w = h[2][0] * x + h[2][1] * y + h[2][2];
+    ((h[0][0] * x + h[0][1] * y + h[0][2]) / w, (h[1][0] * x + h[1][1] * y + h[1][2]) / w)
+}
+
+// Gaussian elimination with partial pivoting.
+fn solve(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    let n = b.len();
+    for col in 0..n {
+        let pivot = (col..n).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+        if a[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+
+        let pivot_row = a[col].clone();
+        for row in col + 1..n {
+            let factor = a[row][col] / pivot_row[col];
+            for (value, pivot) in a[row][col..].iter_mut().zip(&pivot_row[col..]) {
+                *value -= factor * pivot;
+            }
+            b[row] -= factor * b[col];
+        }
+    }
+
+    let mut x = vec![0.0; n];
+    for row in (0..n).rev() {
+        let sum: f64 = (row + 1..n).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - sum) / a[row][row];
+    }
+    Some(x)
+}
+
+// Hartley normalisation: centroid at the origin, mean distance sqrt(2).
+fn normalize(points: &[(f64, f64)]) -> Homography {
+    let n = points.len() as f64;
+    let (cx, cy) = points.iter().fold((0.0, 0.0), |(sx, sy), p| (sx + p.0, sy + p.1));
+    let (cx, cy) = (cx / n, cy / n);
+    let mean = points.iter().map(|p| ((p.0 - cx).powi(2) + (p.1 - cy).powi(2)).sqrt()).sum::<f64>() / n;
+    let scale = if mean > 0.0 { std::f64::consts::SQRT_2 / mean } else { 1.0 };
+    [[scale, 0.0, -scale * cx], [0.0, scale, -scale * cy], [0.0, 0.0, 1.0]]
+}
+
+// Least squares DLT with h33 fixed to 1, mapping `pairs[i].0` onto `pairs[i].1`.
+pub fn fit_homography(pairs: &[Correspondence]) -> Option<Homography> {
+    if pairs.len() < 4 {
+        return None;
+    }
+    let source: Vec<_> = pairs.iter().map(|p| p.0).collect();
+    let target: Vec<_> = pairs.iter().map(|p| p.1).collect();
+    let (ts, tt) = (normalize(&source), normalize(&target));
+
+    let mut ata = vec![vec![0.0; 8]; 8];
+    let mut atb = vec![0.0; 8];
+    for (&s, &t) in source.iter().zip(&target) {
+        let (x, y) = project(&ts, s);
+        let (u, v) = project(&tt, t);
+        for (row, rhs) in [
+            ([x, y, 1.0, 0.0, 0.0, 0.0, -u * x, -u * y], u),
+            ([0.0, 0.0, 0.0, x, y, 1.0, -v * x, -v * y], v),
+        ] {
+            for i in 0..8 {
+                for j in 0..8 {
+                    ata[i][j] += row[i] * row[j];
+                }
+                atb[i] += row[i] * rhs;
+            }
+        }
+    }
+
+    let h = solve(ata, atb)?;
+    let normalized = [[h[0], h[1], h[2]], [h[3], h[4], h[5]], [h[6], h[7], 1.0]];
+    let h = multiply(&invert(&tt)?, &multiply(&normalized, &ts));
+    Some(h.map(|row| row.map(|value| value / h[2][2])))
+}
+
+fn reprojection_error(h: &Homography, pair: &Correspondence) -> f64 {
+    let (x, y) = project(h, pair.0);
+    ((x - pair.1 .0).powi(2) + (y - pair.1 .1).powi(2)).sqrt()
+}
+
+// RANSAC over minimal 4 point samples, refitted on the best inlier set.
+pub fn ransac_homography(pairs: &[Correspondence], options: &PanoramaOptions) -> Option<(Homography, usize)> {
+    if pairs.len() < 4 {
+        return None;
+    }
+    let mut rng = StdRng::seed_from_u64(0x5eed);
+    let count_inliers = |h: &Homography| pairs.iter().filter(|p| reprojection_error(h, p) < options.ransac_threshold).count();
+
+    let mut best: Option<(Homography, usize)> = None;
+    for _ in 0..options.iterations {
+        let mut sample: Vec<usize> = Vec::with_capacity(4);
+        while sample.len() < 4 {
+            let index = rng.gen_range(0..pairs.len());
+            if !sample.contains(&index) {
+                sample.push(index);
+            }
+        }
+        let minimal: Vec<_> = sample.iter().map(|&i| pairs[i]).collect();
+        let Some(h) = fit_homography(&minimal) else { continue };
+
+        let inliers = count_inliers(&h);
+        if inliers > best.map_or(0, |(_, count)| count) {
+            best = Some((h, inliers));
+        }
+    }
+
+    let (h, _) = best?;
+    let inliers: Vec<_> = pairs.iter().filter(|p| reprojection_error(&h, p) < options.ransac_threshold).cloned().collect();
+    let refined = fit_homography(&inliers).unwrap_or(h);
+    Some((refined, count_inliers(&refined)))
+}
+
+// Homography taking `image` coordinates into `reference` coordinates.
+fn pairwise_homography(image: &RgbImage, reference: &RgbImage, options: &PanoramaOptions) -> Result<(Homography, usize), Error> {
+    let gray = |img: &RgbImage| image::DynamicImage::ImageRgb8(img.clone()).to_luma8();
+    let (keypoints, descriptors) = features::detect_and_compute(&gray(image), &options.orb);
+    let (reference_keypoints, reference_descriptors) = features::detect_and_compute(&gray(reference), &options.orb);
+
+    let pairs: Vec<_> = features::match_descriptors(&descriptors, &reference_descriptors, options.orb.ratio)
+        .into_iter()
+        .map(|m| {
+            let (a, b) = (keypoints[m.query], reference_keypoints[m.train]);
+            ((a.x as f64, a.y as f64), (b.x as f64, b.y as f64))
+        })
+        .collect();
+
+    ransac_homography(&pairs, options)
+        .filter(|&(_, inliers)| inliers >= options.min_inliers)
+        .ok_or_else(|| anyhow!("not enough matching features ({} matches)", pairs.len()))
+}
+
+fn sample_bilinear(img: &RgbImage, x: f64, y: f64) -> [f64; 3] {
+    let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+    let (x1, y1) = ((x0 + 1).min(img.width() - 1), (y0 + 1).min(img.height() - 1));
+    let (fx, fy) = (x - x0 as f64, y - y0 as f64);
+
+    let mut out = [0.0; 3];
+    for (c, value) in out.iter_mut().enumerate() {
+        let p = |px: u32, py: u32| img.get_pixel(px, py).0[c] as f64;
+        let top = p(x0, y0) * (1.0 - fx) + p(x1, y0) * fx;
+        let bottom = p(x0, y1) * (1.0 - fx) + p(x1, y1) * fx;
+        *value = top * (1.0 - fy) + bottom * fy;
+    }
+    out
+}
+
+// Stitches overlapping images taken left to right (or in any consistent
+// order where each image overlaps the previous one). The middle image is
+// kept undistorted and seams are feathered by distance to the image border.
+pub fn stitch(images: &[RgbImage], options: &PanoramaOptions) -> Result<RgbImage, Error> {
+    if images.len() < 2 {
+        return Err(anyhow!("Need at least two images to stitch"));
+    }
+
+    let mut to_first = vec![IDENTITY];
+    for i in 1..images.len() {
+        let (h, _) = pairwise_homography(&images[i], &images[i - 1], options)
+            .map_err(|e| anyhow!("Images {} and {} do not overlap: {}", i, i + 1, e))?;
+        to_first.push(multiply(&to_first[i - 1], &h));
+    }
+
+    let middle = images.len() / 2;
+    let from_middle = invert(&to_first[middle]).ok_or_else(|| anyhow!("Degenerate homography"))?;
+    let transforms: Vec<Homography> = to_first.iter().map(|h| multiply(&from_middle, h)).collect();
+
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (f64::MAX, f64::MAX, f64::MIN, f64::MIN);
+    for (image, h) in images.iter().zip(&transforms) {
+        let (w, hgt) = (image.width() as f64, image.height() as f64);
+        for corner in [(0.0, 0.0), (w, 0.0), (0.0, hgt), (w, hgt)] {
+            let (x, y) = project(h, corner);
+            min_x = min_x.min(x);
+            min_y = min_y.min(y);
+            max_x = max_x.max(x);
+            max_y = max_y.max(y);
+        }
+    }
+    let (width, height) = ((max_x - min_x).ceil(), (max_y - min_y).ceil());
+    if !(width * height).is_finite() || width * height > MAX_CANVAS_PIXELS {
+        return Err(anyhow!("Panorama would be {}x{}, the images probably do not line up", width, height));
+    }
+
+    let offset = [[1.0, 0.0, -min_x], [0.0, 1.0, -min_y], [0.0, 0.0, 1.0]];
+    let inverses: Vec<Homography> = transforms
+        .iter()
+        .map(|h| invert(&multiply(&offset, h)).ok_or_else(|| anyhow!("Degenerate homography")))
+        .collect::<Result<_, Error>>()?;
+
+    let canvas = ImageBuffer::from_fn(width as u32, height as u32, |x, y| {
+        let (mut sum, mut total) = ([0.0; 3], 0.0);
+        for (image, inverse) in images.iter().zip(&inverses) {
+            let (sx, sy) = project(inverse, (x as f64 + 0.5, y as f64 + 0.5));
+            let (sx, sy) = (sx - 0.5, sy - 0.5);
+            let (w, h) = (image.width() as f64, image.height() as f64);
+            if sx < 0.0 || sy < 0.0 || sx > w - 1.0 || sy > h - 1.0 {
+                continue;
+            }
+
+            let weight = (sx + 1.0).min(w - sx).min(sy + 1.0).min(h - sy);
+            let color = sample_bilinear(image, sx, sy);
+            for c in 0..3 {
+                sum[c] += color[c] * weight;
+            }
+            total += weight;
+        }
+
+        if total > 0.0 {
+            Rgb(sum.map(|value| (value / total).round().clamp(0.0, 255.0) as u8))
+        } else {
+            Rgb([0, 0, 0])
+        }
+    });
+
+    Ok(canvas)
+}
+
+pub fn stitch_files(paths: &[String], options: &PanoramaOptions) -> Result<RgbImage, Error> {
+    let images = paths
+        .iter()
+        .map(|path| Ok(vision::CompVision::new(path)?.image.to_rgb8()))
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    stitch(&images, options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_stitch_two_crops() {
+        let mut rng = StdRng::seed_from_u64(11);
+        let blocks: Vec<[u8; 3]> = (0..40 * 30).map(|_| rng.gen()).collect();
+        let scene = RgbImage::from_fn(240, 120, |x, y| Rgb(blocks[((y / 6) * 40 + x / 6) as usize]));
+
+        let left = image::imageops::crop_imm(&scene, 0, 0, 150, 120).to_image();
+        let right = image::imageops::crop_imm(&scene, 90, 0, 150, 120).to_image();
+
+        let panorama = stitch(&[left, right], &PanoramaOptions::default()).unwrap();
+
+        assert!(panorama.width().abs_diff(240) <= 2, "width {}", panorama.width());
+        assert!(panorama.height().abs_diff(120) <= 2, "height {}", panorama.height());
+    }
+}
@@ -0,0 +1,243 @@
+use crate::cv::filter::{self, Filter, Param};
+use anyhow::{anyhow, Error};
+use image::{imageops, RgbImage};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+pub const DEFAULT_PIPELINE: &str = "grayscale|gaussian:sigma=1.4|sobel|threshold:otsu";
+
+// One filter of a pipeline with the parameters it was written with, kept in
+// order so the pipeline can be printed back.
+pub struct Stage {
+    pub name: &'static str,
+    pub params: Vec<(String, String)>,
+    filter: Box<dyn Filter>,
+}
+
+// Registered filters applied one after the other, written as
+//
+//     grayscale|gaussian:sigma=1.4|sobel|threshold:otsu
+//
+// Stages are separated by `|`, arguments follow the filter name after `:`
+// and are separated by `,`. Arguments are `key=value`, or bare values that
+// fill the filter's parameters in declaration order, so `threshold:otsu` is
+// `threshold:level=otsu`.
+pub struct Pipeline {
+    pub stages: Vec<Stage>,
+    // Filter only this part of the frame and leave the rest untouched.
+    pub roi: Option<Roi>,
+}
+
+// Region of interest in frame pixels, after frames are scaled.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Roi {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Pipeline {
+    pub fn parse(text: &str) -> Result<Pipeline, Error> {
+        if text.trim().is_empty() {
+            return Err(anyhow!("Empty pipeline"));
+        }
+
+        let stages = text
+            .split('|')
+            .enumerate()
+            .map(|(i, stage)| {
+                parse_stage(stage.trim()).map_err(|e| anyhow!("stage {} '{}': {}", i + 1, stage.trim(), e))
+            })
+            .collect::<Result<Vec<Stage>, Error>>()?;
+
+        Ok(Pipeline { stages, roi: None })
+    }
+
+    // Builds a pipeline from filter names and parameters that are already
+    // split, as read from a preset file.
+    pub fn from_stages(stages: Vec<(String, Vec<(String, String)>)>) -> Result<Pipeline, Error> {
+        if stages.is_empty() {
+            return Err(anyhow!("Empty pipeline"));
+        }
+
+        let stages = stages
+            .into_iter()
+            .enumerate()
+            .map(|(i, (name, params))| {
+                find_entry(&name)
+                    .and_then(|entry| Stage::new(entry, params))
+                    .map_err(|e| anyhow!("stage {} '{}': {}", i + 1, name, e))
+            })
+            .collect::<Result<Vec<Stage>, Error>>()?;
+
+        Ok(Pipeline { stages, roi: None })
+    }
+}
+
+fn parse_stage(stage: &str) -> Result<Stage, Error> {
+    let (name, arguments) = match stage.split_once(':') {
+        Some((name, arguments)) => (name.trim(), Some(arguments)),
+        None => (stage, None),
+    };
+    let entry = find_entry(name)?;
+
+    let mut params: Vec<(String, String)> = Vec::new();
+    let mut keyed = false;
+    for argument in arguments.into_iter().flat_map(|arguments| arguments.split(',')) {
+        let argument = argument.trim();
+        match argument.split_once('=') {
+            Some((key, value)) => {
+                keyed = true;
+                params.push((key.trim().to_string(), value.trim().to_string()));
+            }
+            None => {
+                if keyed {
+                    return Err(anyhow!("positional argument '{}' after a key=value argument", argument));
+                }
+                let param = entry.params.get(params.len()).ok_or_else(|| {
+                    anyhow!("too many arguments, {} takes {}", entry.name, entry.params.len())
+                })?;
+                params.push((param.name.to_string(), argument.to_string()));
+            }
+        }
+    }
+
+    Stage::new(entry, params)
+}
+
+fn find_entry(name: &str) -> Result<&'static filter::Entry, Error> {
+    if name.is_empty() {
+        return Err(anyhow!("missing filter name"));
+    }
+    if name == Pipeline::name() {
+        return Err(anyhow!("pipelines cannot be nested"));
+    }
+    filter::find(name).ok_or_else(|| {
+        let names: Vec<&str> = filter::registry()
+            .iter()
+            .map(|entry| entry.name)
+            .filter(|&name| name != Pipeline::name())
+            .collect();
+        anyhow!("unknown filter '{}', expected one of {}", name, names.join(", "))
+    })
+}
+
+impl Stage {
+    // Unlike a single filter in a form, a stage only takes the filter's own
+    // parameters, so a misspelt key is an error instead of a silent default.
+    fn new(entry: &'static filter::Entry, params: Vec<(String, String)>) -> Result<Stage, Error> {
+        for (i, (key, value)) in params.iter().enumerate() {
+            if value.is_empty() {
+                return Err(anyhow!("missing value for '{}'", key));
+            }
+            if !entry.params.iter().any(|param| param.name == key) {
+                let names: Vec<&str> = entry.params.iter().map(|param| param.name).collect();
+                return Err(match names.is_empty() {
+                    true => anyhow!("{} takes no parameters", entry.name),
+                    false => anyhow!("unknown parameter '{}', expected one of {}", key, names.join(", ")),
+                });
+            }
+            if params[..i].iter().any(|(name, _)| name == key) {
+                return Err(anyhow!("'{}' is given twice", key));
+            }
+        }
+
+        let filter = entry.build(&params.iter().cloned().collect())?;
+        Ok(Stage { name: entry.name, params, filter })
+    }
+}
+
+impl fmt::Display for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, stage) in self.stages.iter().enumerate() {
+            if i > 0 {
+                write!(f, "|")?;
+            }
+            write!(f, "{}", stage.name)?;
+            for (j, (key, value)) in stage.params.iter().enumerate() {
+                write!(f, "{}{}={}", if j == 0 { ':' } else { ',' }, key, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Filter for Pipeline {
+    fn name() -> &'static str { "pipeline" }
+    fn label() -> &'static str { "Pipeline" }
+
+    fn params() -> Vec<Param> {
+        vec![Param::text("pipeline", DEFAULT_PIPELINE, "Filters separated by |, arguments as name:key=value,...")]
+    }
+
+    fn from_params(params: &HashMap<String, String>) -> Result<Pipeline, Error> {
+        Pipeline::parse(params.get("pipeline").map_or(DEFAULT_PIPELINE, |text| text.as_str()))
+    }
+
+    fn stateful(&self) -> bool {
+        self.stages.iter().any(|stage| stage.filter.stateful())
+    }
+
+    fn apply(&mut self, frame: &RgbImage) -> Result<RgbImage, Error> {
+        let (width, height) = frame.dimensions();
+        let region = match self.roi {
+            Some(roi) => {
+                if roi.x >= width || roi.y >= height {
+                    return Err(anyhow!("roi at ({}, {}) lies outside the {}x{} frame", roi.x, roi.y, width, height));
+                }
+                Roi { width: roi.width.min(width - roi.x), height: roi.height.min(height - roi.y), ..roi }
+            }
+            None => Roi { x: 0, y: 0, width, height },
+        };
+
+        let mut test_img = imageops::crop_imm(frame, region.x, region.y, region.width, region.height).to_image();
+        for stage in &mut self.stages {
+            test_img = stage.filter.apply(&test_img).map_err(|e| anyhow!("{}: {}", stage.name, e))?;
+        }
+        if self.roi.is_none() {
+            return Ok(test_img);
+        }
+
+        let mut out = frame.clone();
+        imageops::replace(&mut out, &test_img, region.x as i64, region.y as i64);
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_parses_and_runs_a_pipeline() {
+        let mut pipeline = Pipeline::parse(" grayscale | gaussian:1.4 |sobel|threshold:otsu,invert=true").unwrap();
+        assert_eq!(
+            pipeline.to_string(),
+            "grayscale|gaussian:sigma=1.4|sobel|threshold:level=otsu,invert=true"
+        );
+
+        let frame = RgbImage::from_fn(40, 30, |x, _| if x < 20 { image::Rgb([20, 20, 20]) } else { image::Rgb([230, 230, 230]) });
+        let out = pipeline.apply(&frame).unwrap();
+        assert_eq!(out.dimensions(), frame.dimensions());
+        // Inverted edge map: the boundary is black, flat areas white.
+        assert_eq!(out.get_pixel(20, 15).0, [0, 0, 0]);
+        assert_eq!(out.get_pixel(5, 15).0, [255, 255, 255]);
+    }
+
+    #[test]
+    pub fn test_reports_the_failing_stage() {
+        let error = |text: &str| Pipeline::parse(text).err().unwrap().to_string();
+
+        assert!(error("").contains("Empty pipeline"));
+        assert!(error("grayscale|gausian").contains("stage 2 'gausian': unknown filter 'gausian'"));
+        assert!(error("gaussian:sigma=soft").contains("expected a number"));
+        assert!(error("gaussian:radius=2").contains("unknown parameter 'radius', expected one of sigma"));
+        assert!(error("sobel:3").contains("too many arguments"));
+        assert!(error("threshold:invert=true,otsu").contains("positional argument"));
+        assert!(error("gaussian:1,sigma=2").contains("given twice"));
+        assert!(error("grayscale||sobel").contains("stage 2 '': missing filter name"));
+        assert!(error("pipeline:sobel").contains("cannot be nested"));
+    }
+}
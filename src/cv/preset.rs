@@ -0,0 +1,254 @@
+use crate::cv::{helper, pipeline};
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+// Directory the server loads presets from at startup.
+pub const PRESETS_DIR: &str = "./presets";
+
+// A reusable filter configuration stored as a TOML or JSON file:
+//
+//     name = "edges"
+//     description = "Binary edge map"
+//
+//     [[filters]]
+//     filter = "grayscale"
+//
+//     [[filters]]
+//     filter = "gaussian"
+//     params = { sigma = 1.4 }
+//
+//     [output]
+//     width = 640
+//     fps = 15
+//
+//     [roi]
+//     x = 0
+//     y = 0
+//     width = 320
+//     height = 240
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Preset {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub filters: Vec<FilterSpec>,
+    #[serde(default)]
+    pub output: OutputSpec,
+    pub roi: Option<pipeline::Roi>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FilterSpec {
+    pub filter: String,
+    // Sorted so a listed preset always shows its parameters the same way.
+    #[serde(default)]
+    pub params: BTreeMap<String, ParamValue>,
+}
+
+// Parameter values keep their natural type in the file, `sigma = 1.4` rather
+// than `sigma = "1.4"`, and are turned back into the strings filters parse.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ParamValue {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Text(String),
+}
+
+impl fmt::Display for ParamValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParamValue::Bool(value) => write!(f, "{}", value),
+            ParamValue::Int(value) => write!(f, "{}", value),
+            ParamValue::Float(value) => write!(f, "{}", value),
+            ParamValue::Text(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+// Output settings, a size or rate left out follows the uploaded video.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutputSpec {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fps: Option<f32>,
+    pub container: Option<String>,
+    pub codec: Option<String>,
+    pub crf: Option<u32>,
+    pub bitrate: Option<String>,
+    pub pix_fmt: Option<String>,
+    // Set to false to drop the source's audio.
+    pub audio: Option<bool>,
+}
+
+impl Preset {
+    pub fn from_toml(text: &str) -> Result<Preset, Error> {
+        let preset: Preset = toml::from_str(text)?;
+        preset.validate()?;
+        Ok(preset)
+    }
+
+    pub fn from_json(text: &str) -> Result<Preset, Error> {
+        let preset: Preset = serde_json::from_str(text)?;
+        preset.validate()?;
+        Ok(preset)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Preset, Error> {
+        let text = fs::read_to_string(path)?;
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => Preset::from_toml(&text),
+            Some("json") => Preset::from_json(&text),
+            _ => Err(anyhow!("Presets must be .toml or .json files")),
+        }
+    }
+
+    // Builds the pipeline once, so a preset with a bad filter or parameter
+    // is rejected when it is loaded rather than when it is first used.
+    fn validate(&self) -> Result<(), Error> {
+        if self.name.trim().is_empty() {
+            return Err(anyhow!("Preset name must not be empty"));
+        }
+        if let Some(roi) = self.roi {
+            if roi.width == 0 || roi.height == 0 {
+                return Err(anyhow!("roi width and height must be positive"));
+            }
+        }
+        self.video_options().validate()?;
+        self.pipeline()?;
+        Ok(())
+    }
+
+    // A new pipeline for every job, filters keep state between frames.
+    pub fn pipeline(&self) -> Result<pipeline::Pipeline, Error> {
+        let stages = self
+            .filters
+            .iter()
+            .map(|spec| {
+                let params = spec.params.iter().map(|(key, value)| (key.clone(), value.to_string())).collect();
+                (spec.filter.clone(), params)
+            })
+            .collect();
+
+        let mut pipeline = pipeline::Pipeline::from_stages(stages)?;
+        pipeline.roi = self.roi;
+        Ok(pipeline)
+    }
+
+    pub fn video_options(&self) -> helper::VideoOptions {
+        let defaults = helper::VideoOptions::default();
+        let output = &self.output;
+        helper::VideoOptions {
+            width: output.width,
+            height: output.height,
+            fps: output.fps,
+            container: output.container.clone().unwrap_or(defaults.container),
+            codec: output.codec.clone(),
+            crf: output.crf,
+            bitrate: output.bitrate.clone(),
+            pix_fmt: output.pix_fmt.clone(),
+            audio: output.audio.unwrap_or(defaults.audio),
+        }
+    }
+}
+
+// Every preset in `dir`, sorted by name. A missing directory has no presets;
+// an invalid file or a name used twice is an error naming the file.
+pub fn load_dir(dir: &str) -> Result<Vec<Preset>, Error> {
+    let mut presets: Vec<Preset> = Vec::new();
+    if !Path::new(dir).is_dir() {
+        return Ok(presets);
+    }
+
+    for file in helper::get_all_files_in_folder(dir)? {
+        let path = Path::new(dir).join(&file);
+        if !matches!(path.extension().and_then(|extension| extension.to_str()), Some("toml" | "json")) {
+            continue;
+        }
+
+        let preset = Preset::from_file(&path).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+        if presets.iter().any(|other| other.name == preset.name) {
+            return Err(anyhow!("{}: preset '{}' is defined twice", path.display(), preset.name));
+        }
+        presets.push(preset);
+    }
+
+    presets.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(presets)
+}
+
+pub fn find<'a>(presets: &'a [Preset], name: &str) -> Option<&'a Preset> {
+    presets.iter().find(|preset| preset.name == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_toml_and_json_presets_agree() {
+        let toml = r#"
+            name = "edges"
+
+            [[filters]]
+            filter = "grayscale"
+
+            [[filters]]
+            filter = "gaussian"
+            params = { sigma = 2 }
+
+            [[filters]]
+            filter = "threshold"
+            params = { level = "otsu", invert = true }
+
+            [output]
+            width = 320
+            codec = "libx265"
+
+            [roi]
+            x = 10
+            y = 10
+            width = 100
+            height = 50
+        "#;
+        let json = r#"{
+            "name": "edges",
+            "filters": [
+                { "filter": "grayscale" },
+                { "filter": "gaussian", "params": { "sigma": 2 } },
+                { "filter": "threshold", "params": { "level": "otsu", "invert": true } }
+            ],
+            "output": { "width": 320, "codec": "libx265" },
+            "roi": { "x": 10, "y": 10, "width": 100, "height": 50 }
+        }"#;
+
+        for preset in [Preset::from_toml(toml).unwrap(), Preset::from_json(json).unwrap()] {
+            let pipeline = preset.pipeline().unwrap();
+            assert_eq!(pipeline.to_string(), "grayscale|gaussian:sigma=2|threshold:invert=true,level=otsu");
+            assert_eq!(pipeline.roi, Some(pipeline::Roi { x: 10, y: 10, width: 100, height: 50 }));
+
+            let video = preset.video_options();
+            assert_eq!((video.width, video.height, video.codec.as_deref()), (Some(320), None, Some("libx265")));
+            assert_eq!(video.fps, None);
+        }
+    }
+
+    #[test]
+    pub fn test_invalid_presets_are_rejected() {
+        let error = |text: &str| Preset::from_toml(text).err().unwrap().to_string();
+
+        let bad_param = "name = \"x\"\n[[filters]]\nfilter = \"gaussian\"\nparams = { sigma = \"soft\" }";
+        assert!(error(bad_param).contains("stage 1 'gaussian'"), "{}", error(bad_param));
+        assert!(error("name = \"x\"\nfilters = []").contains("Empty pipeline"));
+        assert!(error("name = \"x\"\n[[filters]]\nfilter = \"sobel\"\n[output]\ncodec = \"h264\"").contains("Unknown codec"));
+        assert!(error("name = \"x\"\ncolour = 1\n[[filters]]\nfilter = \"sobel\"").contains("unknown field"));
+    }
+}
@@ -0,0 +1,265 @@
+use crate::cv::{helper, stream};
+use anyhow::{anyhow, Error};
+use image::{imageops, ImageBuffer, Rgb, RgbImage};
+use serde::Serialize;
+use std::collections::HashMap;
+use indicatif::{ProgressBar, ProgressStyle};
+
+#[derive(Debug, Clone)]
+pub struct SceneOptions {
+    // A cut needs a histogram distance this many standard deviations above
+    // the mean of the preceding window...
+    pub sensitivity: f32,
+    // ...and at least this absolute distance, so static clips don't cut on noise.
+    pub min_distance: f32,
+    pub window: usize,
+    pub min_shot_length: f32,
+}
+
+impl Default for SceneOptions {
+    fn default() -> SceneOptions {
+        SceneOptions {
+            sensitivity: 3.0,
+            min_distance: 0.25,
+            window: 20,
+            min_shot_length: 0.5,
+        }
+    }
+}
+
+impl SceneOptions {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<SceneOptions, Error> {
+        let mut options = SceneOptions::default();
+
+        if let Some(sensitivity) = params.get("sensitivity") {
+            options.sensitivity = sensitivity.parse()?;
+        }
+        if let Some(min_distance) = params.get("min_distance") {
+            options.min_distance = min_distance.parse()?;
+        }
+        if let Some(window) = params.get("window") {
+            options.window = window.parse()?;
+            if options.window == 0 {
+                return Err(anyhow!("window must be at least one frame"));
+            }
+        }
+        if let Some(min_shot_length) = params.get("min_shot_length") {
+            options.min_shot_length = min_shot_length.parse()?;
+        }
+
+        Ok(options)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Shot {
+    pub start: f32,
+    pub end: f32,
+    pub keyframe: f32,
+    pub keyframe_index: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Scenes {
+    pub cuts: Vec<f32>,
+    pub shots: Vec<Shot>,
+}
+
+const BINS_PER_CHANNEL: usize = 8;
+
+// Joint RGB histogram with 8 bins per channel, normalised to sum to one.
+pub fn color_histogram(img: &RgbImage) -> Vec<f32> {
+    let mut histogram = vec![0f32; BINS_PER_CHANNEL * BINS_PER_CHANNEL * BINS_PER_CHANNEL];
+    let shift = 8 - BINS_PER_CHANNEL.trailing_zeros();
+
+    for pixel in img.pixels() {
+        let r = (pixel.0[0] >> shift) as usize;
+        let g = (pixel.0[1] >> shift) as usize;
+        let b = (pixel.0[2] >> shift) as usize;
+        histogram[(r * BINS_PER_CHANNEL + g) * BINS_PER_CHANNEL + b] += 1.0;
+    }
+
+    let total = img.width() as f32 * img.height() as f32;
+    if total > 0.0 {
+        for value in histogram.iter_mut() {
+            *value /= total;
+        }
+    }
+
+    histogram
+}
+
+// Total variation distance, 0 for identical histograms and 1 for disjoint ones.
+pub fn histogram_distance(a: &[f32], b: &[f32]) -> f32 {
+    0.5 * a.iter().zip(b).map(|(x, y)| (x - y).abs()).sum::<f32>()
+}
+
+// Returns the indices of the frames that start a new shot. `distances[i]` is
+// the distance between frame `i` and frame `i + 1`.
+pub fn detect_cuts(distances: &[f32], fps: f32, options: &SceneOptions) -> Vec<usize> {
+    let min_shot_frames = (options.min_shot_length * fps).round() as usize;
+    let mut cuts = Vec::new();
+    let mut last_cut = 0;
+
+    for (i, &distance) in distances.iter().enumerate() {
+        let frame = i + 1;
+        if distance < options.min_distance || frame - last_cut < min_shot_frames {
+            continue;
+        }
+
+        // The window only looks back into the current shot, a previous cut
+        // would otherwise inflate the deviation right after it.
+        let from = i.saturating_sub(options.window).max(last_cut);
+        let window = &distances[from..i];
+        let threshold = if window.is_empty() {
+            options.min_distance
+        } else {
+            let mean = window.iter().sum::<f32>() / window.len() as f32;
+            let variance = window.iter().map(|d| (d - mean) * (d - mean)).sum::<f32>() / window.len() as f32;
+            mean + options.sensitivity * variance.sqrt()
+        };
+
+        if distance > threshold {
+            cuts.push(frame);
+            last_cut = frame;
+        }
+    }
+
+    cuts
+}
+
+pub fn detect_scenes(video_path: &str, options: &SceneOptions) -> Result<Scenes, Error>{
+    let video = helper::VideoOptions::analysis();
+    let reader = stream::FrameReader::open(video_path, &video)?;
+    let fps = reader.fps();
+
+    println!("Fetching frames from {} ..", video_path);
+
+    let pb = ProgressBar::new(reader.expected_frames);
+    pb.set_style(ProgressStyle::default_bar()
+        .template("{msg} {bar:40.cyan/blue} {percent:>3}%")?
+        .progress_chars("█▌▐"));
+
+    let mut histograms = Vec::with_capacity(reader.expected_frames as usize);
+    for frame in reader{
+        histograms.push(color_histogram(&frame?));
+        pb.inc(1);
+    }
+
+    let distances: Vec<f32> = histograms
+        .windows(2)
+        .map(|pair| histogram_distance(&pair[0], &pair[1]))
+        .collect();
+    let cuts = detect_cuts(&distances, fps, options);
+
+    let mut bounds = vec![0];
+    bounds.extend(&cuts);
+    bounds.push(histograms.len());
+
+    let mut shots = Vec::new();
+    for bound in bounds.windows(2) {
+        let (start, end) = (bound[0], bound[1]);
+        if start == end {
+            continue;
+        }
+
+        let keyframe_index = representative_frame(&histograms[start..end]) + start;
+        shots.push(Shot {
+            start: start as f32 / fps,
+            end: end as f32 / fps,
+            keyframe: keyframe_index as f32 / fps,
+            keyframe_index,
+        });
+    }
+
+    Ok(Scenes {
+        cuts: cuts.iter().map(|&frame| frame as f32 / fps).collect(),
+        shots,
+    })
+}
+
+// Decodes the video again and keeps only the keyframe of every shot, so the
+// rest never has to be held in memory.
+pub fn extract_keyframes(video_path: &str, shots: &[Shot]) -> Result<Vec<RgbImage>, Error>{
+    let video = helper::VideoOptions::analysis();
+    let mut wanted = shots.iter().map(|shot| shot.keyframe_index).peekable();
+    let mut keyframes = Vec::with_capacity(shots.len());
+    for (i, frame) in stream::FrameReader::open(video_path, &video)?.enumerate(){
+        let frame = frame?;
+        if wanted.peek() == Some(&i) {
+            keyframes.push(frame);
+            wanted.next();
+        }
+        if wanted.peek().is_none() {
+            break;
+        }
+    }
+    if keyframes.len() != shots.len() {
+        return Err(anyhow!("{} ended before its last keyframe", video_path));
+    }
+
+    Ok(keyframes)
+}
+
+// The frame whose histogram is closest to the average histogram of the shot.
+fn representative_frame(histograms: &[Vec<f32>]) -> usize {
+    let mut mean = vec![0f32; histograms[0].len()];
+    for histogram in histograms {
+        for (m, value) in mean.iter_mut().zip(histogram) {
+            *m += value / histograms.len() as f32;
+        }
+    }
+
+    histograms
+        .iter()
+        .map(|histogram| histogram_distance(histogram, &mean))
+        .enumerate()
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map_or(0, |(i, _)| i)
+}
+
+// Lays the keyframes out in a grid of half size thumbnails.
+pub fn contact_sheet(keyframes: &[RgbImage], columns: u32) -> RgbImage {
+    const SPACING: u32 = 4;
+
+    let Some(first) = keyframes.first() else {
+        return ImageBuffer::new(1, 1);
+    };
+
+    let thumb_width = (first.width() / 2).max(1);
+    let thumb_height = (first.height() / 2).max(1);
+    let columns = columns.clamp(1, keyframes.len() as u32);
+    let rows = (keyframes.len() as u32).div_ceil(columns);
+
+    let mut sheet = ImageBuffer::from_pixel(
+        columns * (thumb_width + SPACING) + SPACING,
+        rows * (thumb_height + SPACING) + SPACING,
+        Rgb([32, 32, 32]),
+    );
+
+    for (i, keyframe) in keyframes.iter().enumerate() {
+        let thumb = imageops::resize(keyframe, thumb_width, thumb_height, imageops::FilterType::Triangle);
+        let x = SPACING + (i as u32 % columns) * (thumb_width + SPACING);
+        let y = SPACING + (i as u32 / columns) * (thumb_height + SPACING);
+        imageops::replace(&mut sheet, &thumb, x as i64, y as i64);
+    }
+
+    sheet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_detect_cuts_adapts_to_noise() {
+        // Jitter that is above `min_distance` on its own but should not cut.
+        let mut distances: Vec<f32> = (0..40).map(|i| if i % 2 == 0 { 0.3 } else { 0.2 }).collect();
+        distances[10] = 0.9;
+        distances[32] = 0.95;
+
+        let cuts = detect_cuts(&distances, 10.0, &SceneOptions::default());
+
+        assert_eq!(cuts, vec![11, 33]);
+    }
+}
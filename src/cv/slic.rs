@@ -0,0 +1,303 @@
+use crate::cv::components::Labels;
+use crate::cv::filter::{Filter, Param};
+use anyhow::{anyhow, Error};
+use image::{ImageBuffer, Rgb, RgbImage};
+use std::collections::{HashMap, VecDeque};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Render {
+    Boundaries,
+    Mosaic,
+}
+
+#[derive(Debug, Clone)]
+pub struct SlicOptions {
+    // Approximate number of superpixels per frame.
+    pub superpixels: u32,
+    // Weight of spatial distance against Lab colour distance, higher values
+    // give more regular, grid like superpixels.
+    pub compactness: f32,
+    pub iterations: u32,
+    pub render: Render,
+}
+
+impl Default for SlicOptions {
+    fn default() -> SlicOptions {
+        SlicOptions {
+            superpixels: 200,
+            compactness: 10.0,
+            iterations: 10,
+            render: Render::Boundaries,
+        }
+    }
+}
+
+impl SlicOptions {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<SlicOptions, Error> {
+        let mut options = SlicOptions::default();
+
+        if let Some(superpixels) = params.get("superpixels") {
+            options.superpixels = superpixels.parse()?;
+            if options.superpixels == 0 {
+                return Err(anyhow!("superpixels must be at least 1"));
+            }
+        }
+        if let Some(compactness) = params.get("compactness") {
+            options.compactness = compactness.parse()?;
+            if options.compactness <= 0.0 {
+                return Err(anyhow!("compactness must be positive"));
+            }
+        }
+        if let Some(iterations) = params.get("iterations") {
+            options.iterations = iterations.parse()?;
+        }
+        if let Some(render) = params.get("render") {
+            options.render = match render.as_str() {
+                "boundaries" => Render::Boundaries,
+                "mosaic" => Render::Mosaic,
+                other => return Err(anyhow!("Unknown superpixel render '{}'", other)),
+            };
+        }
+
+        Ok(options)
+    }
+}
+
+// sRGB to CIE Lab under the D65 white point.
+pub fn rgb_to_lab(pixel: &Rgb<u8>) -> [f32; 3] {
+    let linear = |c: u8| {
+        let c = c as f32 / 255.0;
+        if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+    };
+    let (r, g, b) = (linear(pixel.0[0]), linear(pixel.0[1]), linear(pixel.0[2]));
+
+    let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+
+    let f = |t: f32| if t > 0.008856 { t.cbrt() } else { 7.787 * t + 16.0 / 116.0 };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Center {
+    lab: [f32; 3],
+    x: f32,
+    y: f32,
+}
+
+fn lab_distance(a: &[f32; 3], b: &[f32; 3]) -> f32 {
+    a.iter().zip(b).map(|(p, q)| (p - q) * (p - q)).sum()
+}
+
+// Simple Linear Iterative Clustering (Achanta et al.): k-means in Lab + xy
+// where each centre only searches a 2S x 2S window around itself. Labels
+// start at 1 and every pixel is labelled.
+pub fn slic(frame: &RgbImage, options: &SlicOptions) -> Labels {
+    let (width, height) = frame.dimensions();
+    let (w, h) = (width as usize, height as usize);
+    let lab: Vec<[f32; 3]> = frame.pixels().map(rgb_to_lab).collect();
+
+    let step = ((w * h) as f32 / options.superpixels as f32).sqrt().max(1.0);
+    let at = |x: usize, y: usize| &lab[y * w + x];
+
+    // Seeds on a regular grid, moved to the lowest gradient position in
+    // their 3x3 neighbourhood so they do not start on an edge.
+    let gradient = |x: usize, y: usize| {
+        if x == 0 || y == 0 || x + 1 >= w || y + 1 >= h {
+            return f32::MAX;
+        }
+        lab_distance(at(x + 1, y), at(x - 1, y)) + lab_distance(at(x, y + 1), at(x, y - 1))
+    };
+    let mut centers = Vec::new();
+    let mut y = step / 2.0;
+    while y < h as f32 {
+        let mut x = step / 2.0;
+        while x < w as f32 {
+            let (cx, cy) = (x as usize, y as usize);
+            let (bx, by) = (cx.saturating_sub(1)..=(cx + 1).min(w - 1))
+                .flat_map(|nx| (cy.saturating_sub(1)..=(cy + 1).min(h - 1)).map(move |ny| (nx, ny)))
+                .min_by(|a, b| gradient(a.0, a.1).total_cmp(&gradient(b.0, b.1)))
+                .unwrap_or((cx, cy));
+            centers.push(Center { lab: *at(bx, by), x: bx as f32, y: by as f32 });
+            x += step;
+        }
+        y += step;
+    }
+
+    let spatial = (options.compactness / step).powi(2);
+    let mut labels = vec![0usize; w * h];
+    let mut distances = vec![f32::MAX; w * h];
+
+    for _ in 0..options.iterations {
+        distances.iter_mut().for_each(|d| *d = f32::MAX);
+
+        for (k, center) in centers.iter().enumerate() {
+            let x0 = (center.x - step).max(0.0) as usize;
+            let y0 = (center.y - step).max(0.0) as usize;
+            let x1 = ((center.x + step) as usize).min(w - 1);
+            let y1 = ((center.y + step) as usize).min(h - 1);
+            for y in y0..=y1 {
+                for x in x0..=x1 {
+                    let dxy = (x as f32 - center.x).powi(2) + (y as f32 - center.y).powi(2);
+                    let distance = lab_distance(at(x, y), &center.lab) + dxy * spatial;
+                    let index = y * w + x;
+                    if distance < distances[index] {
+                        distances[index] = distance;
+                        labels[index] = k;
+                    }
+                }
+            }
+        }
+
+        let mut sums = vec![([0f32; 3], 0f32, 0f32, 0f32); centers.len()];
+        for (index, &k) in labels.iter().enumerate() {
+            let sum = &mut sums[k];
+            for (total, value) in sum.0.iter_mut().zip(lab[index]) {
+                *total += value;
+            }
+            sum.1 += (index % w) as f32;
+            sum.2 += (index / w) as f32;
+            sum.3 += 1.0;
+        }
+        for (center, (lab_sum, x_sum, y_sum, count)) in centers.iter_mut().zip(sums) {
+            if count > 0.0 {
+                *center = Center { lab: lab_sum.map(|v| v / count), x: x_sum / count, y: y_sum / count };
+            }
+        }
+    }
+
+    enforce_connectivity(&labels, width, height, (step * step / 4.0) as usize)
+}
+
+// Relabels the clusters into 4-connected segments and merges segments
+// smaller than `min_size` into the neighbouring segment found before them.
+fn enforce_connectivity(labels: &[usize], width: u32, height: u32, min_size: usize) -> Labels {
+    let (w, h) = (width as usize, height as usize);
+    let neighbours = |index: usize| {
+        let (x, y) = (index % w, index / w);
+        let mut out = Vec::with_capacity(4);
+        if x > 0 { out.push(index - 1); }
+        if y > 0 { out.push(index - w); }
+        if x + 1 < w { out.push(index + 1); }
+        if y + 1 < h { out.push(index + w); }
+        out
+    };
+
+    let mut relabelled = vec![0u32; w * h];
+    let mut count = 0;
+    for start in 0..w * h {
+        if relabelled[start] != 0 {
+            continue;
+        }
+
+        count += 1;
+        let adjacent = neighbours(start).into_iter().map(|n| relabelled[n]).find(|&l| l != 0);
+        let mut segment = vec![start];
+        let mut queue = VecDeque::from([start]);
+        relabelled[start] = count;
+        while let Some(index) = queue.pop_front() {
+            for neighbour in neighbours(index) {
+                if relabelled[neighbour] == 0 && labels[neighbour] == labels[start] {
+                    relabelled[neighbour] = count;
+                    segment.push(neighbour);
+                    queue.push_back(neighbour);
+                }
+            }
+        }
+
+        if segment.len() < min_size {
+            if let Some(adjacent) = adjacent {
+                for index in segment {
+                    relabelled[index] = adjacent;
+                }
+                count -= 1;
+            }
+        }
+    }
+
+    Labels { width, height, labels: relabelled, count }
+}
+
+// Frame with the superpixel borders drawn in yellow.
+pub fn draw_boundaries(frame: &RgbImage, labels: &Labels) -> RgbImage {
+    ImageBuffer::from_fn(frame.width(), frame.height(), |x, y| {
+        let label = labels.get(x, y);
+        let border = (x + 1 < frame.width() && labels.get(x + 1, y) != label)
+            || (y + 1 < frame.height() && labels.get(x, y + 1) != label);
+        if border { Rgb([255, 255, 0]) } else { *frame.get_pixel(x, y) }
+    })
+}
+
+// Every superpixel filled with its mean colour.
+pub fn mosaic(frame: &RgbImage, labels: &Labels) -> RgbImage {
+    let mut sums = vec![[0u64; 4]; labels.count as usize + 1];
+    for (x, y, pixel) in frame.enumerate_pixels() {
+        let sum = &mut sums[labels.get(x, y) as usize];
+        for (total, &value) in sum.iter_mut().zip(&pixel.0) {
+            *total += value as u64;
+        }
+        sum[3] += 1;
+    }
+
+    ImageBuffer::from_fn(frame.width(), frame.height(), |x, y| {
+        let sum = sums[labels.get(x, y) as usize];
+        let count = sum[3].max(1);
+        Rgb([(sum[0] / count) as u8, (sum[1] / count) as u8, (sum[2] / count) as u8])
+    })
+}
+
+// Clusters the frame into superpixels and renders them as `options.render`
+// asks.
+pub fn draw_superpixels(frame: &RgbImage, options: &SlicOptions) -> RgbImage {
+    let labels = slic(frame, options);
+    match options.render {
+        Render::Boundaries => draw_boundaries(frame, &labels),
+        Render::Mosaic => mosaic(frame, &labels),
+    }
+}
+
+impl Filter for SlicOptions {
+    fn name() -> &'static str { "superpixels" }
+    fn label() -> &'static str { "Superpixels" }
+
+    fn params() -> Vec<Param> {
+        let defaults = SlicOptions::default();
+        vec![
+            Param::int("superpixels", defaults.superpixels, "Approximate number of superpixels"),
+            Param::float("compactness", defaults.compactness, "Spatial against colour weight"),
+            Param::int("iterations", defaults.iterations, "Clustering iterations"),
+            Param::choice("render", &["boundaries", "mosaic"], "boundaries", "Borders or mean colours"),
+        ]
+    }
+
+    fn from_params(params: &HashMap<String, String>) -> Result<SlicOptions, Error> {
+        SlicOptions::from_params(params)
+    }
+
+    fn apply(&mut self, frame: &RgbImage) -> Result<RgbImage, Error> {
+        Ok(draw_superpixels(frame, self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_superpixels_follow_colour_edges() {
+        let frame = RgbImage::from_fn(60, 40, |x, _| if x < 27 { Rgb([200, 30, 30]) } else { Rgb([30, 30, 200]) });
+        let options = SlicOptions { superpixels: 12, ..SlicOptions::default() };
+
+        let labels = slic(&frame, &options);
+
+        assert!((6..=24).contains(&labels.count), "{} superpixels", labels.count);
+        let mut colours: HashMap<u32, Rgb<u8>> = HashMap::new();
+        for (x, y, pixel) in frame.enumerate_pixels() {
+            let label = labels.get(x, y);
+            assert!(label != 0);
+            assert_eq!(*colours.entry(label).or_insert(*pixel), *pixel, "superpixel {} crosses the edge", label);
+        }
+    }
+}
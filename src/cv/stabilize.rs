@@ -0,0 +1,363 @@
+use crate::cv::{vision, helper, stream};
+use anyhow::{anyhow, Error};
+use image::{imageops, DynamicImage, GrayImage, ImageBuffer, Rgb, RgbImage};
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use indicatif::{ProgressBar, ProgressStyle};
+
+#[derive(Debug, Clone)]
+pub struct StabilizeOptions {
+    // Frames on either side averaged into the smoothed camera path.
+    pub smoothing: usize,
+    // Upper bound for the zoom used to push the moving borders out of frame.
+    pub max_zoom: f32,
+}
+
+impl Default for StabilizeOptions {
+    fn default() -> StabilizeOptions {
+        StabilizeOptions {
+            smoothing: 15,
+            max_zoom: 1.25,
+        }
+    }
+}
+
+impl StabilizeOptions {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<StabilizeOptions, Error> {
+        let mut options = StabilizeOptions::default();
+
+        // Prefixed, /filter passes the same fields to the filter, and
+        // cartoon has a smoothing of its own.
+        if let Some(smoothing) = params.get("stabilize_smoothing") {
+            options.smoothing = smoothing.parse()?;
+        }
+        if let Some(max_zoom) = params.get("max_zoom") {
+            options.max_zoom = max_zoom.parse()?;
+            if options.max_zoom < 1.0 {
+                return Err(anyhow!("max_zoom must be at least 1"));
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Complex {
+    re: f32,
+    im: f32,
+}
+
+impl Complex {
+    fn mul(self, other: Complex) -> Complex {
+        Complex {
+            re: self.re * other.re - self.im * other.im,
+            im: self.re * other.im + self.im * other.re,
+        }
+    }
+
+    fn conj(self) -> Complex {
+        Complex { re: self.re, im: -self.im }
+    }
+
+    fn norm(self) -> f32 {
+        (self.re * self.re + self.im * self.im).sqrt()
+    }
+}
+
+// Iterative radix-2 Cooley-Tukey, `data.len()` has to be a power of two.
+fn fft(data: &mut [Complex], inverse: bool) {
+    let n = data.len();
+
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            data.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let angle = if inverse { 2.0 * PI / len as f32 } else { -2.0 * PI / len as f32 };
+        let step = Complex { re: angle.cos(), im: angle.sin() };
+        for start in (0..n).step_by(len) {
+            let mut w = Complex { re: 1.0, im: 0.0 };
+            for k in 0..len / 2 {
+                let a = data[start + k];
+                let b = data[start + k + len / 2].mul(w);
+                data[start + k] = Complex { re: a.re + b.re, im: a.im + b.im };
+                data[start + k + len / 2] = Complex { re: a.re - b.re, im: a.im - b.im };
+                w = w.mul(step);
+            }
+        }
+        len <<= 1;
+    }
+
+    if inverse {
+        for value in data.iter_mut() {
+            value.re /= n as f32;
+            value.im /= n as f32;
+        }
+    }
+}
+
+fn fft_2d(data: &mut [Complex], width: usize, height: usize, inverse: bool) {
+    for row in data.chunks_mut(width) {
+        fft(row, inverse);
+    }
+
+    let mut column = vec![Complex::default(); height];
+    for x in 0..width {
+        for y in 0..height {
+            column[y] = data[y * width + x];
+        }
+        fft(&mut column, inverse);
+        for y in 0..height {
+            data[y * width + x] = column[y];
+        }
+    }
+}
+
+// Grayscale frame resized to power of two dimensions with a Hann window
+// applied, ready for phase correlation.
+struct Spectrum {
+    data: Vec<Complex>,
+    width: usize,
+    height: usize,
+}
+
+impl Spectrum {
+    fn new(gray: &GrayImage) -> Spectrum {
+        let width = prev_power_of_two(gray.width());
+        let height = prev_power_of_two(gray.height());
+        let resized = imageops::resize(gray, width, height, imageops::FilterType::Triangle);
+        let (width, height) = (width as usize, height as usize);
+
+        let mut data = Vec::with_capacity(width * height);
+        for (x, y, pixel) in resized.enumerate_pixels() {
+            let wx = 0.5 - 0.5 * (2.0 * PI * x as f32 / (width - 1).max(1) as f32).cos();
+            let wy = 0.5 - 0.5 * (2.0 * PI * y as f32 / (height - 1).max(1) as f32).cos();
+            data.push(Complex { re: pixel.0[0] as f32 * wx * wy, im: 0.0 });
+        }
+        fft_2d(&mut data, width, height, false);
+
+        Spectrum { data, width, height }
+    }
+}
+
+fn prev_power_of_two(value: u32) -> u32 {
+    if value <= 1 { 1 } else { 1 << (31 - value.leading_zeros()) }
+}
+
+// Translation (in spectrum pixels) that moves `previous` onto `current`, found
+// as the peak of the normalised cross-power spectrum.
+fn phase_correlate(previous: &Spectrum, current: &Spectrum) -> (f32, f32) {
+    let (width, height) = (current.width, current.height);
+
+    let mut cross: Vec<Complex> = current
+        .data
+        .iter()
+        .zip(&previous.data)
+        .map(|(a, b)| {
+            let product = a.mul(b.conj());
+            let norm = product.norm().max(1e-6);
+            Complex { re: product.re / norm, im: product.im / norm }
+        })
+        .collect();
+    fft_2d(&mut cross, width, height, true);
+
+    let (peak, _) = cross
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.re.total_cmp(&b.1.re))
+        .unwrap_or((0, &Complex::default()));
+    let (px, py) = (peak % width, peak / width);
+
+    let at = |x: usize, y: usize| cross[(y % height) * width + (x % width)].re;
+    let dx = px as f32 + subpixel(at(px + width - 1, py), at(px, py), at(px + 1, py));
+    let dy = py as f32 + subpixel(at(px, py + height - 1), at(px, py), at(px, py + 1));
+
+    // Peaks past the half way point are negative shifts wrapped around.
+    let dx = if dx > width as f32 / 2.0 { dx - width as f32 } else { dx };
+    let dy = if dy > height as f32 / 2.0 { dy - height as f32 } else { dy };
+
+    (dx, dy)
+}
+
+// Parabolic interpolation of the peak between its two neighbours.
+fn subpixel(left: f32, centre: f32, right: f32) -> f32 {
+    let denominator = left - 2.0 * centre + right;
+    if denominator.abs() < 1e-6 {
+        0.0
+    } else {
+        (0.5 * (left - right) / denominator).clamp(-0.5, 0.5)
+    }
+}
+
+// Per-frame offsets that move the raw camera path onto a moving average of itself.
+pub fn smooth_corrections(motion: &[(f32, f32)], radius: usize) -> Vec<(f32, f32)> {
+    let mut trajectory = Vec::with_capacity(motion.len());
+    let (mut x, mut y) = (0.0, 0.0);
+    for (dx, dy) in motion {
+        x += dx;
+        y += dy;
+        trajectory.push((x, y));
+    }
+
+    (0..trajectory.len())
+        .map(|i| {
+            let from = i.saturating_sub(radius);
+            let to = (i + radius + 1).min(trajectory.len());
+            let window = &trajectory[from..to];
+            let sx = window.iter().map(|p| p.0).sum::<f32>() / window.len() as f32;
+            let sy = window.iter().map(|p| p.1).sum::<f32>() / window.len() as f32;
+            (sx - trajectory[i].0, sy - trajectory[i].1)
+        })
+        .collect()
+}
+
+fn sample_bilinear(img: &RgbImage, x: f32, y: f32) -> Rgb<u8> {
+    let max_x = (img.width() - 1) as f32;
+    let max_y = (img.height() - 1) as f32;
+    let x = x.clamp(0.0, max_x);
+    let y = y.clamp(0.0, max_y);
+
+    let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+    let (x1, y1) = ((x0 + 1).min(img.width() - 1), (y0 + 1).min(img.height() - 1));
+    let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+
+    let p00 = img.get_pixel(x0, y0).0;
+    let p10 = img.get_pixel(x1, y0).0;
+    let p01 = img.get_pixel(x0, y1).0;
+    let p11 = img.get_pixel(x1, y1).0;
+
+    let mut rgb = [0u8; 3];
+    for c in 0..3 {
+        let top = p00[c] as f32 * (1.0 - fx) + p10[c] as f32 * fx;
+        let bottom = p01[c] as f32 * (1.0 - fx) + p11[c] as f32 * fx;
+        rgb[c] = (top * (1.0 - fy) + bottom * fy).round() as u8;
+    }
+
+    Rgb(rgb)
+}
+
+// Shifts the frame by `correction` and zooms in about its centre.
+pub fn warp(frame: &RgbImage, correction: (f32, f32), zoom: f32) -> RgbImage {
+    let (width, height) = frame.dimensions();
+    let (cx, cy) = (width as f32 / 2.0, height as f32 / 2.0);
+    let mut test_img = ImageBuffer::new(width, height);
+
+    for (x, y, pixel) in test_img.enumerate_pixels_mut() {
+        let sx = cx + (x as f32 - cx) / zoom - correction.0;
+        let sy = cy + (y as f32 - cy) / zoom - correction.1;
+        *pixel = sample_bilinear(frame, sx, sy);
+    }
+
+    test_img
+}
+
+// Zoom needed so that the largest correction never exposes the frame border.
+fn crop_zoom(corrections: &[(f32, f32)], width: u32, height: u32, max_zoom: f32) -> f32 {
+    let max_x = corrections.iter().map(|c| c.0.abs()).fold(0.0, f32::max);
+    let max_y = corrections.iter().map(|c| c.1.abs()).fold(0.0, f32::max);
+
+    let zoom_x = width as f32 / (width as f32 - 2.0 * max_x).max(1.0);
+    let zoom_y = height as f32 / (height as f32 - 2.0 * max_y).max(1.0);
+
+    zoom_x.max(zoom_y).clamp(1.0, max_zoom)
+}
+
+// Stabilizes the upload into `output_path`, which can then be fed into any
+// of the filters in place of the original. `video` should be the options of
+// that filter job, so the frames are only scaled once.
+pub fn stabilize_video(video_path: &str, options: &StabilizeOptions, video: &helper::VideoOptions, output_path: &str) -> Result<(), Error>{
+    let reader = stream::FrameReader::open(video_path, video)?;
+    let format = reader.format();
+    let dimensions = reader.dimensions();
+
+    println!("Stabilizing {} ..", video_path);
+
+    let pb = ProgressBar::new(2 * reader.expected_frames);
+    pb.set_style(ProgressStyle::default_bar()
+        .template("{msg} {bar:40.cyan/blue} {percent:>3}%")?
+        .progress_chars("█▌▐"));
+
+    let mut motion = Vec::with_capacity(reader.expected_frames as usize);
+    let mut previous: Option<Spectrum> = None;
+    for frame in reader{
+        let gray = vision::CompVision::to_grayscale(DynamicImage::ImageRgb8(frame?))?;
+        let spectrum = Spectrum::new(&gray);
+
+        let shift = match &previous {
+            Some(previous) => {
+                let (dx, dy) = phase_correlate(previous, &spectrum);
+                // Back from the resized spectrum to frame pixels.
+                (dx * gray.width() as f32 / spectrum.width as f32, dy * gray.height() as f32 / spectrum.height as f32)
+            }
+            None => (0.0, 0.0),
+        };
+        motion.push(shift);
+        previous = Some(spectrum);
+        pb.inc(1);
+    }
+
+    let corrections = smooth_corrections(&motion, options.smoothing);
+    let zoom = crop_zoom(&corrections, dimensions.0, dimensions.1, options.max_zoom);
+
+    // Second pass warps the frames straight into the encoder.
+    // The stabilized video is only read back and encoded again by the
+    // filter job, so it is a lossless x264 MP4 whatever that job writes.
+    let intermediate = helper::VideoOptions {
+        container: helper::VideoOptions::default().container,
+        codec: Some("libx264".to_string()),
+        crf: Some(0),
+        bitrate: None,
+        pix_fmt: Some("yuv444p".to_string()),
+        ..video.clone()
+    };
+    let mut writer = stream::FrameWriter::create(output_path, &format, &intermediate)?;
+    for (frame, correction) in stream::FrameReader::open(video_path, video)?.zip(&corrections){
+        writer.write(&warp(&frame?, *correction, zoom))?;
+        pb.inc(1);
+    }
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_phase_correlation_finds_shift() {
+        let pattern = |x: u32, y: u32| ((x * 37 + y * 91) % 255) as u8 ^ ((x / 5 + y / 3) * 40) as u8;
+        let a = GrayImage::from_fn(64, 64, |x, y| image::Luma([pattern(x, y)]));
+        let b = GrayImage::from_fn(64, 64, |x, y| {
+            image::Luma([pattern((x + 64 - 3) % 64, (y + 64 - 5) % 64)])
+        });
+
+        let (dx, dy) = phase_correlate(&Spectrum::new(&a), &Spectrum::new(&b));
+
+        assert!((dx - 3.0).abs() < 0.5, "dx = {}", dx);
+        assert!((dy - 5.0).abs() < 0.5, "dy = {}", dy);
+    }
+
+    #[test]
+    pub fn test_smoothing_is_separate_from_cartoon() {
+        let params = [("filter", "cartoon"), ("stabilize", "true"), ("smoothing", "3"), ("stabilize_smoothing", "20")]
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+
+        assert_eq!(StabilizeOptions::from_params(&params).unwrap().smoothing, 20);
+        assert_eq!(crate::cv::cartoon::CartoonOptions::from_params(&params).unwrap().smoothing, 3);
+
+        let params = [("smoothing".to_string(), "3".to_string())].into_iter().collect();
+        assert_eq!(StabilizeOptions::from_params(&params).unwrap().smoothing, 15);
+    }
+}
@@ -0,0 +1,373 @@
+use crate::cv::helper;
+use anyhow::{anyhow, Error};
+use image::RgbImage;
+use rayon::prelude::*;
+use std::fs;
+use std::io::{Read, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::thread::JoinHandle;
+
+// Frames in video order, as decoded by a `FrameReader` or produced by a filter.
+pub type Frames<'a> = Box<dyn Iterator<Item = Result<RgbImage, Error>> + 'a>;
+
+// Keeps ffmpeg's stderr drained so a chatty process never blocks on a full
+// pipe, and hands the text back for error messages.
+fn drain_stderr(child: &mut Child) -> Option<JoinHandle<String>> {
+    let mut stderr = child.stderr.take()?;
+    Some(std::thread::spawn(move || {
+        let mut text = String::new();
+        let _ = stderr.read_to_string(&mut text);
+        text
+    }))
+}
+
+fn wait(child: &mut Child, stderr: Option<JoinHandle<String>>) -> Result<(), Error> {
+    let status = child.wait()?;
+    let stderr = stderr.and_then(|handle| handle.join().ok()).unwrap_or_default();
+    if !status.success() {
+        return Err(anyhow!("ffmpeg failed with status {}: {}", status, stderr.trim()));
+    }
+    Ok(())
+}
+
+// Size, rate and pixel format of decoded frames and the audio that goes
+// with them, what the encoder needs to write them back out like the source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Format {
+    pub width: u32,
+    pub height: u32,
+    pub fps: f32,
+    pub pix_fmt: String,
+    pub audio: Option<Audio>,
+}
+
+// Audio track of the video the frames were decoded from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Audio {
+    pub path: String,
+    pub codec: String,
+}
+
+// Decodes a video into RGB frames through a pipe from ffmpeg, one frame in
+// memory at a time and nothing written to disk.
+pub struct FrameReader {
+    child: Child,
+    stdout: ChildStdout,
+    stderr: Option<JoinHandle<String>>,
+    width: u32,
+    height: u32,
+    fps: f32,
+    pix_fmt: String,
+    audio: Option<Audio>,
+    // Estimated from the duration, for progress bars.
+    pub expected_frames: u64,
+    done: bool,
+}
+
+impl FrameReader {
+    pub fn open(video_path: &str, options: &helper::VideoOptions) -> Result<FrameReader, Error> {
+        let probe = helper::probe(video_path)?;
+        let (width, height) = options.frame_size((probe.width, probe.height));
+        if width > helper::MAX_SIDE || height > helper::MAX_SIDE {
+            return Err(anyhow!("Frames of {}x{} are larger than {} on a side, ask for a smaller width or height", width, height, helper::MAX_SIDE));
+        }
+        let fps = options.frame_rate(probe.fps);
+
+        let mut child = Command::new("ffmpeg")
+            .args([
+                "-v", "error",
+                "-i", video_path,
+                "-vf", &format!("fps={},scale={}:{}", fps, width, height),
+                "-f", "rawvideo",
+                "-pix_fmt", "rgb24",
+                "-",
+            ])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let stdout = child.stdout.take().ok_or_else(|| anyhow!("ffmpeg has no stdout"))?;
+        let stderr = drain_stderr(&mut child);
+
+        Ok(FrameReader {
+            child,
+            stdout,
+            stderr,
+            width,
+            height,
+            fps,
+            pix_fmt: probe.pix_fmt,
+            audio: probe.audio_codec.map(|codec| Audio { path: video_path.to_string(), codec }),
+            expected_frames: (probe.duration * fps).ceil() as u64,
+            done: false,
+        })
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    // Frames per second the frames come out at, for timestamps.
+    pub fn fps(&self) -> f32 {
+        self.fps
+    }
+
+    pub fn format(&self) -> Format {
+        Format {
+            width: self.width,
+            height: self.height,
+            fps: self.fps,
+            pix_fmt: self.pix_fmt.clone(),
+            audio: self.audio.clone(),
+        }
+    }
+
+    fn next_frame(&mut self) -> Result<Option<RgbImage>, Error> {
+        let size = (self.width as usize)
+            .checked_mul(self.height as usize)
+            .and_then(|pixels| pixels.checked_mul(3))
+            .ok_or_else(|| anyhow!("Frames of {}x{} do not fit in memory", self.width, self.height))?;
+        let mut buffer = vec![0u8; size];
+        let mut filled = 0;
+        while filled < buffer.len() {
+            match self.stdout.read(&mut buffer[filled..])? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+
+        if filled == 0 {
+            self.done = true;
+            wait(&mut self.child, self.stderr.take())?;
+            return Ok(None);
+        }
+        if filled < buffer.len() {
+            self.done = true;
+            wait(&mut self.child, self.stderr.take())?;
+            return Err(anyhow!("ffmpeg ended in the middle of a frame"));
+        }
+
+        let frame = RgbImage::from_raw(self.width, self.height, buffer).ok_or_else(|| anyhow!("Bad frame size"))?;
+        Ok(Some(frame))
+    }
+}
+
+impl Iterator for FrameReader {
+    type Item = Result<RgbImage, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let frame = self.next_frame();
+        if frame.is_err() {
+            self.done = true;
+        }
+        frame.transpose()
+    }
+}
+
+impl Drop for FrameReader {
+    // Stopping early, on an error or because the caller had enough frames,
+    // must not leave ffmpeg running.
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+// Encodes RGB frames piped into ffmpeg's stdin.
+pub struct FrameWriter {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    stderr: Option<JoinHandle<String>>,
+    width: u32,
+    height: u32,
+    gif: Option<GifPasses>,
+}
+
+// A GIF palette has to be fitted to every frame before the first one can be
+// written. The pipe only stores the frames losslessly next to the output,
+// then one pass writes the palette and another maps the frames onto it, so
+// ffmpeg never holds the whole clip in memory.
+struct GifPasses {
+    frames_path: String,
+    palette_path: String,
+    output_path: String,
+    encoder_args: Vec<String>,
+}
+
+impl GifPasses {
+    fn encode(&self) -> Result<(), Error> {
+        let result = helper::run_ffmpeg(&[
+            "-v", "error",
+            "-y",
+            "-i", &self.frames_path,
+            "-vf", "palettegen",
+            &self.palette_path,
+        ])
+        .and_then(|_| {
+            let mut args = vec!["-v", "error", "-y", "-i", &self.frames_path, "-i", &self.palette_path];
+            args.extend(self.encoder_args.iter().map(|arg| arg.as_str()));
+            args.push(&self.output_path);
+            helper::run_ffmpeg(&args)
+        });
+        self.remove_files();
+        result
+    }
+
+    fn remove_files(&self) {
+        let _ = fs::remove_file(&self.frames_path);
+        let _ = fs::remove_file(&self.palette_path);
+    }
+}
+
+impl FrameWriter {
+    // Frames are encoded at the rate they were decoded at, so the output
+    // plays as long as the source and its audio, when kept, stays in sync.
+    // The audio is cut to the frames that were written.
+    pub fn create(output_path: &str, format: &Format, options: &helper::VideoOptions) -> Result<FrameWriter, Error> {
+        let (width, height) = (format.width, format.height);
+        let mut args: Vec<String> = [
+            "-v", "error",
+            "-y",
+            "-f", "rawvideo",
+            "-pix_fmt", "rgb24",
+            "-s", &format!("{}x{}", width, height),
+            "-framerate", &format.fps.to_string(),
+            "-i", "-",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+
+        let container = options.container()?;
+        let audio = format
+            .audio
+            .as_ref()
+            .filter(|_| options.audio)
+            .and_then(|audio| Some((audio, container.audio_codec(&audio.codec)?)));
+        match audio {
+            Some((audio, codec)) => args.extend(
+                ["-i", &audio.path, "-map", "0:v", "-map", "1:a:0", "-c:a", codec, "-shortest"]
+                    .iter()
+                    .map(|arg| arg.to_string()),
+            ),
+            None => args.push("-an".to_string()),
+        }
+        let gif = match container.name {
+            "gif" => Some(GifPasses {
+                frames_path: format!("{}.frames.mkv", output_path),
+                palette_path: format!("{}.palette.png", output_path),
+                output_path: output_path.to_string(),
+                encoder_args: options.encoder_args(&format.pix_fmt)?,
+            }),
+            _ => None,
+        };
+        match &gif {
+            Some(gif) => args.extend(["-c:v", "ffv1", "-f", "matroska", &gif.frames_path].iter().map(|arg| arg.to_string())),
+            None => {
+                args.extend(options.encoder_args(&format.pix_fmt)?);
+                args.push(output_path.to_string());
+            }
+        }
+
+        let mut child = Command::new("ffmpeg")
+            .args(&args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take();
+        let stderr = drain_stderr(&mut child);
+
+        Ok(FrameWriter { child, stdin, stderr, width, height, gif })
+    }
+
+    pub fn write(&mut self, frame: &RgbImage) -> Result<(), Error> {
+        if frame.dimensions() != (self.width, self.height) {
+            return Err(anyhow!(
+                "Frame is {}x{}, the video is {}x{}",
+                frame.width(), frame.height(), self.width, self.height
+            ));
+        }
+        let stdin = self.stdin.as_mut().ok_or_else(|| anyhow!("Writer is already finished"))?;
+        if stdin.write_all(frame.as_raw()).is_err() {
+            // ffmpeg went away, its exit status says why.
+            self.stdin = None;
+            wait(&mut self.child, self.stderr.take())?;
+            return Err(anyhow!("ffmpeg stopped reading frames"));
+        }
+        Ok(())
+    }
+
+    // Closes the pipe and waits for the encoder to write the file.
+    pub fn finish(mut self) -> Result<(), Error> {
+        self.stdin = None;
+        wait(&mut self.child, self.stderr.take())?;
+        match self.gif.take() {
+            Some(gif) => gif.encode(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for FrameWriter {
+    fn drop(&mut self) {
+        if self.stdin.take().is_some() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+        if let Some(gif) = self.gif.take() {
+            gif.remove_files();
+        }
+    }
+}
+
+// Runs `f` over every frame on the shared pool, `workers` frames at a time
+// (0 for one per core), and returns the results in frame order. Frames are
+// read one batch at a time, so memory stays bounded however long the video is.
+pub fn map_frames<T, F>(frames: Frames, workers: usize, f: F) -> Result<Vec<T>, Error>
+where
+    T: Send,
+    F: Fn(usize, &RgbImage) -> Result<T, Error> + Sync,
+{
+    let batch = helper::frames_in_flight(workers)?;
+
+    let mut results = Vec::new();
+    let mut frames = frames.enumerate();
+    loop {
+        let chunk = frames
+            .by_ref()
+            .take(batch)
+            .map(|(i, frame)| Ok((i, frame?)))
+            .collect::<Result<Vec<(usize, RgbImage)>, Error>>()?;
+        if chunk.is_empty() {
+            return Ok(results);
+        }
+
+        let mapped = helper::thread_pool().install(|| {
+            chunk
+                .par_iter()
+                .map(|(i, frame)| f(*i, frame))
+                .collect::<Result<Vec<T>, Error>>()
+        })?;
+        results.extend(mapped);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_map_frames_keeps_order() {
+        let frames: Frames = Box::new((0..37u8).map(|i| Ok(RgbImage::from_pixel(4, 3, image::Rgb([i, 0, 0])))));
+
+        let values = map_frames(frames, 4, |i, frame| Ok((i, frame.get_pixel(0, 0).0[0]))).unwrap();
+
+        assert_eq!(values, (0..37).map(|i| (i, i as u8)).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,393 @@
+use crate::cv::{helper, draw, stream};
+use anyhow::{anyhow, Error};
+use image::{GrayImage, ImageBuffer, Luma, Rgb, RgbImage};
+use serde::Serialize;
+use std::collections::HashMap;
+use indicatif::{ProgressBar, ProgressStyle};
+
+#[derive(Debug, Clone)]
+pub struct TrackOptions {
+    // Initial box as fractions of the frame size: x, y, width, height.
+    pub initial: (f32, f32, f32, f32),
+    pub bins: usize,
+    // Pixels that are too grey or too dark have an unreliable hue and are
+    // left out of the histogram and the back projection.
+    pub min_saturation: u8,
+    pub min_value: u8,
+    pub max_iterations: usize,
+}
+
+impl TrackOptions {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<TrackOptions, Error> {
+        let initial = params
+            .get("box")
+            .ok_or_else(|| anyhow!("Missing initial box, expected box=x,y,width,height"))?;
+        let values = initial
+            .split(',')
+            .map(|value| value.trim().parse::<f32>())
+            .collect::<Result<Vec<f32>, _>>()?;
+        let [x, y, width, height] = values[..] else {
+            return Err(anyhow!("Initial box needs four values, got {}", values.len()));
+        };
+        if x < 0.0 || y < 0.0 || width <= 0.0 || height <= 0.0 || x + width > 1.0 || y + height > 1.0 {
+            return Err(anyhow!("Initial box has to lie within the frame, as fractions between 0 and 1"));
+        }
+
+        let mut options = TrackOptions {
+            initial: (x, y, width, height),
+            bins: 16,
+            min_saturation: 60,
+            min_value: 32,
+            max_iterations: 10,
+        };
+
+        if let Some(bins) = params.get("bins") {
+            options.bins = bins.parse()?;
+            if !(1..=180).contains(&options.bins) {
+                return Err(anyhow!("bins must be between 1 and 180"));
+            }
+        }
+        if let Some(min_saturation) = params.get("min_saturation") {
+            options.min_saturation = min_saturation.parse()?;
+        }
+        if let Some(min_value) = params.get("min_value") {
+            options.min_value = min_value.parse()?;
+        }
+
+        Ok(options)
+    }
+}
+
+// Box as returned by CAMShift: centre, size along its own axes and the
+// rotation of its width axis in degrees.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct RotatedBox {
+    pub frame: usize,
+    pub time: f32,
+    pub center: (f32, f32),
+    pub size: (f32, f32),
+    pub angle: f32,
+    pub lost: bool,
+}
+
+impl RotatedBox {
+    pub fn corners(&self) -> [(f32, f32); 4] {
+        let (cos, sin) = (self.angle.to_radians().cos(), self.angle.to_radians().sin());
+        let (hw, hh) = (self.size.0 / 2.0, self.size.1 / 2.0);
+        let corner = |u: f32, v: f32| (self.center.0 + u * cos - v * sin, self.center.1 + u * sin + v * cos);
+        [corner(-hw, -hh), corner(hw, -hh), corner(hw, hh), corner(-hw, hh)]
+    }
+}
+
+// Boxes are in pixels of the extracted frames, whose size is given alongside.
+#[derive(Debug, Clone, Serialize)]
+pub struct Track {
+    pub width: u32,
+    pub height: u32,
+    pub boxes: Vec<RotatedBox>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Window {
+    x: i64,
+    y: i64,
+    width: i64,
+    height: i64,
+}
+
+impl Window {
+    fn clamp(self, width: u32, height: u32) -> Window {
+        let w = self.width.clamp(1, width as i64);
+        let h = self.height.clamp(1, height as i64);
+        Window {
+            x: self.x.clamp(0, width as i64 - w),
+            y: self.y.clamp(0, height as i64 - h),
+            width: w,
+            height: h,
+        }
+    }
+}
+
+// Hue in OpenCV's 0..180 range, saturation and value in 0..=255.
+fn hsv(pixel: &Rgb<u8>) -> (f32, u8, u8) {
+    let (r, g, b) = (pixel.0[0] as f32, pixel.0[1] as f32, pixel.0[2] as f32);
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let delta = max - min;
+
+    let hue = if delta == 0.0 {
+        0.0
+    } else if max == r {
+        60.0 * ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+    let saturation = if max == 0.0 { 0.0 } else { 255.0 * delta / max };
+
+    (hue / 2.0, saturation as u8, max as u8)
+}
+
+pub struct CamShift {
+    histogram: Vec<f32>,
+    window: Window,
+    options: TrackOptions,
+}
+
+impl CamShift {
+    pub fn new(frame: &RgbImage, options: TrackOptions) -> CamShift {
+        let (width, height) = frame.dimensions();
+        let (fx, fy, fw, fh) = options.initial;
+        let window = Window {
+            x: (fx * width as f32) as i64,
+            y: (fy * height as f32) as i64,
+            width: (fw * width as f32).round() as i64,
+            height: (fh * height as f32).round() as i64,
+        }
+        .clamp(width, height);
+
+        let mut histogram = vec![0f32; options.bins];
+        for y in window.y..window.y + window.height {
+            for x in window.x..window.x + window.width {
+                if let Some(bin) = hue_bin(frame.get_pixel(x as u32, y as u32), &options) {
+                    histogram[bin] += 1.0;
+                }
+            }
+        }
+
+        // Scaled so the back projection uses the full 0..=255 range.
+        let max = histogram.iter().cloned().fold(0.0, f32::max);
+        if max > 0.0 {
+            for value in histogram.iter_mut() {
+                *value = *value * 255.0 / max;
+            }
+        }
+
+        CamShift { histogram, window, options }
+    }
+
+    pub fn back_project(&self, frame: &RgbImage) -> GrayImage {
+        let (width, height) = frame.dimensions();
+        let mut test_img = ImageBuffer::new(width, height);
+
+        for (x, y, pixel) in frame.enumerate_pixels() {
+            let probability = hue_bin(pixel, &self.options).map_or(0.0, |bin| self.histogram[bin]);
+            test_img.put_pixel(x, y, Luma([probability as u8]));
+        }
+
+        test_img
+    }
+
+    // Runs mean shift from the previous window and then adapts the window to
+    // the size and orientation of the probability blob it converged on.
+    pub fn track(&mut self, frame: &RgbImage) -> RotatedBox {
+        let (width, height) = frame.dimensions();
+        let probability = self.back_project(frame);
+
+        let mut moments = Moments::over(&probability, self.window);
+        for _ in 0..self.options.max_iterations {
+            if moments.m00 <= 0.0 {
+                break;
+            }
+            let (cx, cy) = moments.centroid();
+            let moved = Window {
+                x: (cx - self.window.width as f32 / 2.0).round() as i64,
+                y: (cy - self.window.height as f32 / 2.0).round() as i64,
+                ..self.window
+            }
+            .clamp(width, height);
+
+            let converged = moved == self.window;
+            self.window = moved;
+            moments = Moments::over(&probability, self.window);
+            if converged {
+                break;
+            }
+        }
+
+        let lost = moments.m00 <= 0.0;
+        let rotated = if lost {
+            RotatedBox {
+                frame: 0,
+                time: 0.0,
+                center: (
+                    self.window.x as f32 + self.window.width as f32 / 2.0,
+                    self.window.y as f32 + self.window.height as f32 / 2.0,
+                ),
+                size: (self.window.width as f32, self.window.height as f32),
+                angle: 0.0,
+                lost,
+            }
+        } else {
+            let rotated = moments.rotated_box();
+            let (min_x, min_y, max_x, max_y) = rotated.corners().iter().fold(
+                (f32::MAX, f32::MAX, f32::MIN, f32::MIN),
+                |(a, b, c, d), &(x, y)| (a.min(x), b.min(y), c.max(x), d.max(y)),
+            );
+            self.window = Window {
+                x: min_x.floor() as i64,
+                y: min_y.floor() as i64,
+                width: (max_x - min_x).ceil().max(3.0) as i64,
+                height: (max_y - min_y).ceil().max(3.0) as i64,
+            }
+            .clamp(width, height);
+            rotated
+        };
+
+        rotated
+    }
+}
+
+fn hue_bin(pixel: &Rgb<u8>, options: &TrackOptions) -> Option<usize> {
+    let (hue, saturation, value) = hsv(pixel);
+    if saturation < options.min_saturation || value < options.min_value {
+        return None;
+    }
+    Some(((hue / 180.0 * options.bins as f32) as usize).min(options.bins - 1))
+}
+
+// Raw image moments of the back projection inside a window, in frame coordinates.
+struct Moments {
+    m00: f32,
+    m10: f32,
+    m01: f32,
+    m20: f32,
+    m02: f32,
+    m11: f32,
+}
+
+impl Moments {
+    fn over(probability: &GrayImage, window: Window) -> Moments {
+        let mut moments = Moments { m00: 0.0, m10: 0.0, m01: 0.0, m20: 0.0, m02: 0.0, m11: 0.0 };
+
+        for y in window.y..window.y + window.height {
+            for x in window.x..window.x + window.width {
+                let p = probability.get_pixel(x as u32, y as u32).0[0] as f32;
+                if p == 0.0 {
+                    continue;
+                }
+                let (fx, fy) = (x as f32, y as f32);
+                moments.m00 += p;
+                moments.m10 += p * fx;
+                moments.m01 += p * fy;
+                moments.m20 += p * fx * fx;
+                moments.m02 += p * fy * fy;
+                moments.m11 += p * fx * fy;
+            }
+        }
+
+        moments
+    }
+
+    fn centroid(&self) -> (f32, f32) {
+        (self.m10 / self.m00, self.m01 / self.m00)
+    }
+
+    // Box with the blob's principal axes, sized at two standard deviations
+    // either side of the centroid.
+    fn rotated_box(&self) -> RotatedBox {
+        let (cx, cy) = self.centroid();
+        let a = self.m20 / self.m00 - cx * cx;
+        let b = self.m11 / self.m00 - cx * cy;
+        let c = self.m02 / self.m00 - cy * cy;
+
+        let root = (((a - c) / 2.0).powi(2) + b * b).sqrt();
+        let major = ((a + c) / 2.0 + root).max(0.0);
+        let minor = ((a + c) / 2.0 - root).max(0.0);
+        let angle = 0.5 * (2.0 * b).atan2(a - c);
+
+        RotatedBox {
+            frame: 0,
+            time: 0.0,
+            center: (cx, cy),
+            size: (4.0 * major.sqrt(), 4.0 * minor.sqrt()),
+            angle: angle.to_degrees(),
+            lost: false,
+        }
+    }
+}
+
+// Tracks the object through the whole upload, decoded with `video`. With an
+// `output_path` the boxes are also drawn onto the frames and encoded there.
+pub fn track_video(video_path: &str, options: &TrackOptions, video: &helper::VideoOptions, output_path: Option<&str>) -> Result<Track, Error>{
+    let reader = stream::FrameReader::open(video_path, video)?;
+    let dimensions = reader.dimensions();
+    let fps = reader.fps();
+    let mut writer = match output_path {
+        Some(output_path) => Some(stream::FrameWriter::create(output_path, &reader.format(), video)?),
+        None => None,
+    };
+
+    println!("Tracking object in {} ..", video_path);
+
+    let pb = ProgressBar::new(reader.expected_frames);
+    pb.set_style(ProgressStyle::default_bar()
+        .template("{msg} {bar:40.cyan/blue} {percent:>3}%")?
+        .progress_chars("█▌▐"));
+
+    let mut tracker: Option<CamShift> = None;
+    let mut boxes = Vec::with_capacity(reader.expected_frames as usize);
+
+    for (i, frame) in reader.enumerate(){
+        let mut frame = frame?;
+
+        let tracker = tracker.get_or_insert_with(|| CamShift::new(&frame, options.clone()));
+        let mut rotated = tracker.track(&frame);
+        rotated.frame = i;
+        rotated.time = i as f32 / fps;
+        boxes.push(rotated);
+
+        if let Some(writer) = writer.as_mut() {
+            let color = if rotated.lost { Rgb([255, 0, 0]) } else { Rgb([0, 255, 0]) };
+            draw::draw_polygon(&mut frame, &rotated.corners(), color, 2);
+            writer.write(&frame)?;
+        }
+        pb.inc(1);
+    }
+
+    if let Some(writer) = writer {
+        writer.finish()?;
+    }
+
+    Ok(Track {
+        width: dimensions.0,
+        height: dimensions.1,
+        boxes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_camshift_follows_blob() {
+        let frame_with_blob = |cx: i64, cy: i64| {
+            RgbImage::from_fn(80, 60, |x, y| {
+                if (x as i64 - cx).abs() < 6 && (y as i64 - cy).abs() < 4 {
+                    Rgb([230, 30, 30])
+                } else {
+                    Rgb([120, 120, 120])
+                }
+            })
+        };
+
+        let mut params = HashMap::new();
+        params.insert("box".to_string(), "0.2,0.2,0.2,0.2".to_string());
+        let options = TrackOptions::from_params(&params).unwrap();
+
+        let mut tracker = CamShift::new(&frame_with_blob(24, 18), options);
+        let mut last = None;
+        for step in 0..5 {
+            let rotated = tracker.track(&frame_with_blob(24 + step * 3, 18 + step));
+            assert!(!rotated.lost);
+            last = Some(rotated);
+        }
+
+        let last = last.unwrap();
+        assert!((last.center.0 - 36.0).abs() < 1.5, "{:?}", last);
+        assert!((last.center.1 - 22.0).abs() < 1.5, "{:?}", last);
+        assert!(last.size.0 > last.size.1);
+    }
+}
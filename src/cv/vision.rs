@@ -85,6 +85,147 @@ impl CompVision{
         Ok(test_img)
     }
 
+    pub fn threshold(img: DynamicImage, level: u8) -> Result<ImageBuffer<Luma<u8>, Vec<u8>>, Error>{
+        let gray = CompVision::to_grayscale(img)?;
+        let (width, height) = gray.dimensions();
+
+        let mut test_img = image::ImageBuffer::new(width, height);
+
+        for (x, y, pixel) in gray.enumerate_pixels(){
+            let value = if pixel.0[0] > level { 255 } else { 0 };
+            test_img.put_pixel(x, y, Luma([value]));
+        }
+
+        Ok(test_img)
+    }
+
+    //Binary foreground mask at `level`, or at Otsu's level when none is given.
+    pub fn binarize(img: DynamicImage, level: Option<u8>, invert: bool) -> Result<ImageBuffer<Luma<u8>, Vec<u8>>, Error>{
+        let gray = CompVision::to_grayscale(img)?;
+        let level = level.unwrap_or_else(|| CompVision::otsu_level(&gray));
+        let mut binary = CompVision::threshold(DynamicImage::ImageLuma8(gray), level)?;
+        if invert {
+            image::imageops::invert(&mut binary);
+        }
+        Ok(binary)
+    }
+
+    //Otsu's method: picks the grey level that maximises the variance between
+    //the two classes it splits the histogram into.
+    pub fn otsu_level(gray: &ImageBuffer<Luma<u8>, Vec<u8>>) -> u8{
+        let mut histogram = [0u64; 256];
+        for pixel in gray.pixels(){
+            histogram[pixel.0[0] as usize] += 1;
+        }
+
+        let total = gray.len() as f64;
+        let sum_all: f64 = histogram.iter().enumerate().map(|(i, &count)| i as f64 * count as f64).sum();
+
+        let mut best_level = 0;
+        let mut best_variance = 0.0;
+        let mut weight_background = 0.0;
+        let mut sum_background = 0.0;
+
+        for (level, &count) in histogram.iter().enumerate(){
+            weight_background += count as f64;
+            if weight_background == 0.0 {
+                continue;
+            }
+            let weight_foreground = total - weight_background;
+            if weight_foreground == 0.0 {
+                break;
+            }
+
+            sum_background += level as f64 * count as f64;
+            let mean_background = sum_background / weight_background;
+            let mean_foreground = (sum_all - sum_background) / weight_foreground;
+
+            let variance = weight_background * weight_foreground * (mean_background - mean_foreground).powi(2);
+            if variance > best_variance {
+                best_variance = variance;
+                best_level = level as u8;
+            }
+        }
+
+        best_level
+    }
+
+    //Bilateral filter: a Gaussian blur where each neighbour is also weighted
+    //by how close its colour is, so flat areas are smoothed but edges stay sharp.
+    pub fn bilateral(img: DynamicImage, radius: u32, sigma_color: f32, sigma_space: f32) -> Result<ImageBuffer<Rgb<u8>, Vec<u8>>, Error>{
+        let source = img.to_rgb8();
+        let (width, height) = source.dimensions();
+        let radius = radius as i64;
+
+        let space_weights: Vec<f32> = (-radius..=radius)
+            .flat_map(|dy| (-radius..=radius).map(move |dx| (dx, dy)))
+            .map(|(dx, dy)| (-((dx * dx + dy * dy) as f32) / (2.0 * sigma_space * sigma_space)).exp())
+            .collect();
+        let color_weights: Vec<f32> = (0..=3 * 255 * 255)
+            .map(|d| (-(d as f32) / (2.0 * sigma_color * sigma_color)).exp())
+            .collect();
+
+        let test_img = ImageBuffer::from_fn(width, height, |x, y| {
+            let center = source.get_pixel(x, y).0;
+            let mut sum = [0f32; 3];
+            let mut weight_sum = 0f32;
+            let mut index = 0;
+
+            for dy in -radius..=radius {
+                for dx in -radius..=radius {
+                    let (nx, ny) = (x as i64 + dx, y as i64 + dy);
+                    let space = space_weights[index];
+                    index += 1;
+                    if nx < 0 || ny < 0 || nx >= width as i64 || ny >= height as i64 {
+                        continue;
+                    }
+
+                    let pixel = source.get_pixel(nx as u32, ny as u32).0;
+                    let distance: i32 = (0..3).map(|c| (pixel[c] as i32 - center[c] as i32).pow(2)).sum();
+                    let weight = space * color_weights[distance as usize];
+                    for c in 0..3 {
+                        sum[c] += pixel[c] as f32 * weight;
+                    }
+                    weight_sum += weight;
+                }
+            }
+
+            Rgb(sum.map(|value| (value / weight_sum).round().clamp(0.0, 255.0) as u8))
+        });
+
+        Ok(test_img)
+    }
+
+    //Mean adaptive threshold: a pixel is white when it is brighter than the
+    //mean of its `block` x `block` neighbourhood minus `c`.
+    pub fn adaptive_threshold(gray: &ImageBuffer<Luma<u8>, Vec<u8>>, block: u32, c: i32) -> ImageBuffer<Luma<u8>, Vec<u8>>{
+        let (width, height) = gray.dimensions();
+        let (w, h) = (width as usize, height as usize);
+
+        //Summed area table with a zero row and column in front.
+        let mut integral = vec![0u64; (w + 1) * (h + 1)];
+        for y in 0..h {
+            let mut row = 0u64;
+            for x in 0..w {
+                row += gray.get_pixel(x as u32, y as u32).0[0] as u64;
+                integral[(y + 1) * (w + 1) + x + 1] = integral[y * (w + 1) + x + 1] + row;
+            }
+        }
+
+        let radius = (block / 2) as usize;
+        ImageBuffer::from_fn(width, height, |x, y| {
+            let (x, y) = (x as usize, y as usize);
+            let (x0, y0) = (x.saturating_sub(radius), y.saturating_sub(radius));
+            let (x1, y1) = ((x + radius + 1).min(w), (y + radius + 1).min(h));
+            let sum = integral[y1 * (w + 1) + x1] + integral[y0 * (w + 1) + x0]
+                - integral[y0 * (w + 1) + x1] - integral[y1 * (w + 1) + x0];
+            let mean = sum as f32 / ((x1 - x0) * (y1 - y0)) as f32;
+
+            let value = gray.get_pixel(x as u32, y as u32).0[0] as f32;
+            Luma([if value > mean - c as f32 { 255 } else { 0 }])
+        })
+    }
+
     fn create_gaussian_kernel_2d(radius: usize, sigma: f32) -> Vec<f32> {
         let size = 2 * radius + 1;
         let mut kernel = vec![0.0; size * size];
@@ -270,6 +411,42 @@ impl CompVision{
     }
 
 
+    //Horizontal and vertical Sobel derivatives of the grayscale image, the
+    //building block for the corner and circle detectors. Border pixels are
+    //left at zero like in edge_detection_sobel.
+    pub fn sobel_gradients(img: &ImageBuffer<Luma<u8>, Vec<u8>>) -> Gradients {
+        let (width, height) = img.dimensions();
+        let mut gradients = Gradients::new(width, height);
+
+        if width < 3 || height < 3 {
+            return gradients;
+        }
+
+        let sobel_kernel_x = [[-1, 0, 1], [-2, 0, 2], [-1, 0, 1]];
+        let sobel_kernel_y = [[-1, -2, -1], [0, 0, 0], [1, 2, 1]];
+
+        for y in 1..height-1 {
+            for x in 1..width-1 {
+                let mut gx = 0;
+                let mut gy = 0;
+
+                for ky in 0..3 {
+                    for kx in 0..3 {
+                        let pixel = img.get_pixel(x + kx - 1, y + ky - 1)[0] as i32;
+                        gx += pixel * sobel_kernel_x[ky as usize][kx as usize];
+                        gy += pixel * sobel_kernel_y[ky as usize][kx as usize];
+                    }
+                }
+
+                let index = (y * width + x) as usize;
+                gradients.gx[index] = gx as f32;
+                gradients.gy[index] = gy as f32;
+            }
+        }
+
+        gradients
+    }
+
     pub fn kmeans(img: DynamicImage, palette: usize)-> Result<ImageBuffer<Rgb<u8>, Vec<u8>>, Error> {
         let (width, height) = img.dimensions();
         let mut test_img = image::ImageBuffer::new(width, height);
@@ -390,4 +567,22 @@ impl Vec3d{
         (self.x + vec.x).powf(2.0) + (self.y + vec.y).powf(2.0) + (self.z+vec.z).powf(2.0).powf(0.5)
     }
 
-}
\ No newline at end of file
+}
+
+pub struct Gradients {
+    pub width: u32,
+    pub height: u32,
+    pub gx: Vec<f32>,
+    pub gy: Vec<f32>,
+}
+
+impl Gradients{
+    pub fn new(width: u32, height: u32) -> Gradients{
+        let size = (width * height) as usize;
+        Gradients { width, height, gx: vec![0.0; size], gy: vec![0.0; size] }
+    }
+
+    pub fn magnitude(&self, index: usize) -> f32{
+        (self.gx[index] * self.gx[index] + self.gy[index] * self.gy[index]).sqrt()
+    }
+}
@@ -0,0 +1,312 @@
+use crate::cv::{vision, helper};
+use crate::cv::components::{self, Connectivity, Labels};
+use crate::cv::filter::{Filter, Param};
+use anyhow::{anyhow, Error};
+use image::{DynamicImage, GrayImage, ImageBuffer, Rgb, RgbImage};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Output {
+    Overlay,
+    Labels,
+}
+
+#[derive(Debug, Clone)]
+pub struct WatershedOptions {
+    pub threshold: Option<u8>,
+    pub invert: bool,
+    // Distance transform peaks closer than this many pixels to a higher
+    // peak are not used as markers.
+    pub min_distance: u32,
+    // Peaks lower than this (in pixels from the background) are ignored.
+    pub min_peak: f32,
+    pub output: Output,
+}
+
+impl Default for WatershedOptions {
+    fn default() -> WatershedOptions {
+        WatershedOptions {
+            threshold: None,
+            invert: false,
+            min_distance: 5,
+            min_peak: 2.0,
+            output: Output::Overlay,
+        }
+    }
+}
+
+impl WatershedOptions {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<WatershedOptions, Error> {
+        let mut options = WatershedOptions::default();
+
+        if let Some(threshold) = params.get("threshold") {
+            options.threshold = helper::threshold_level(threshold)?;
+        }
+        if let Some(invert) = params.get("invert") {
+            options.invert = invert.parse()?;
+        }
+        if let Some(min_distance) = params.get("min_distance") {
+            options.min_distance = min_distance.parse()?;
+            if options.min_distance > 50 {
+                return Err(anyhow!("min_distance must be at most 50, got {}", options.min_distance));
+            }
+        }
+        if let Some(min_peak) = params.get("min_peak") {
+            options.min_peak = min_peak.parse()?;
+        }
+        if let Some(output) = params.get("output") {
+            options.output = match output.as_str() {
+                "overlay" => Output::Overlay,
+                "labels" => Output::Labels,
+                other => return Err(anyhow!("Unknown watershed output '{}'", other)),
+            };
+        }
+
+        Ok(options)
+    }
+
+    // Binarizes the frame, then splits touching objects by flooding the
+    // inverted distance transform from its peaks.
+    pub fn segment(&self, frame: DynamicImage) -> Result<Labels, Error> {
+        let binary = vision::CompVision::binarize(frame, self.threshold, self.invert)?;
+        let distance = distance_transform(&binary);
+        let markers = distance_markers(&distance, binary.width(), binary.height(), self.min_distance, self.min_peak);
+
+        let relief: Vec<f32> = distance.iter().map(|d| -d).collect();
+        let mask: Vec<bool> = binary.pixels().map(|pixel| pixel.0[0] > 0).collect();
+        Ok(watershed(&relief, &mask, markers))
+    }
+}
+
+// Far enough to never be the minimum, but finite so the parabola
+// intersections below stay well defined.
+const FAR: f64 = 1e20;
+
+// Lower envelope of the parabolas (q - p)^2 + f(p), one dimension of the
+// Felzenszwalb & Huttenlocher squared distance transform.
+fn squared_distance_1d(f: &[f64], d: &mut [f64]) {
+    let n = f.len();
+    let mut v = vec![0usize; n];
+    let mut z = vec![0f64; n + 1];
+    let mut k = 0;
+    z[0] = f64::NEG_INFINITY;
+    z[1] = f64::INFINITY;
+
+    for q in 1..n {
+        let intersect = |p: usize| {
+            ((f[q] + (q * q) as f64) - (f[p] + (p * p) as f64)) / (2.0 * q as f64 - 2.0 * p as f64)
+        };
+        let mut s = intersect(v[k]);
+        while s <= z[k] {
+            k -= 1;
+            s = intersect(v[k]);
+        }
+        k += 1;
+        v[k] = q;
+        z[k] = s;
+        z[k + 1] = f64::INFINITY;
+    }
+
+    k = 0;
+    for (q, value) in d.iter_mut().enumerate() {
+        while z[k + 1] < q as f64 {
+            k += 1;
+        }
+        let offset = q as f64 - v[k] as f64;
+        *value = offset * offset + f[v[k]];
+    }
+}
+
+// Exact Euclidean distance from every foreground pixel to the nearest
+// background pixel, separable over columns and then rows.
+pub fn distance_transform(binary: &GrayImage) -> Vec<f32> {
+    let (width, height) = (binary.width() as usize, binary.height() as usize);
+    let mut grid: Vec<f64> = binary.pixels().map(|pixel| if pixel.0[0] > 0 { FAR } else { 0.0 }).collect();
+
+    let mut column = vec![0f64; height];
+    let mut out = vec![0f64; height.max(width)];
+    for x in 0..width {
+        for (y, value) in column.iter_mut().enumerate() {
+            *value = grid[y * width + x];
+        }
+        squared_distance_1d(&column, &mut out[..height]);
+        for (y, &value) in out[..height].iter().enumerate() {
+            grid[y * width + x] = value;
+        }
+    }
+
+    for row in grid.chunks_mut(width) {
+        let input = row.to_vec();
+        squared_distance_1d(&input, row);
+    }
+
+    grid.into_iter().map(|value| value.sqrt() as f32).collect()
+}
+
+// Markers at the distance transform peaks: pixels that are the maximum of
+// their (2 * min_distance + 1) window, with touching peak pixels (plateaus)
+// merged into one marker.
+pub fn distance_markers(distance: &[f32], width: u32, height: u32, min_distance: u32, min_peak: f32) -> Labels {
+    let radius = min_distance as i64;
+    let at = |x: i64, y: i64| distance[(y * width as i64 + x) as usize];
+
+    let peaks = GrayImage::from_fn(width, height, |x, y| {
+        let (x, y) = (x as i64, y as i64);
+        let value = at(x, y);
+        if value <= 0.0 || value < min_peak {
+            return image::Luma([0]);
+        }
+        let is_peak = ((y - radius).max(0)..=(y + radius).min(height as i64 - 1))
+            .all(|ny| ((x - radius).max(0)..=(x + radius).min(width as i64 - 1)).all(|nx| at(nx, ny) <= value));
+        image::Luma([if is_peak { 255 } else { 0 }])
+    });
+
+    components::label_components(&peaks, Connectivity::Eight)
+}
+
+// Meyer's flooding: starting from the markers, the lowest unlabelled pixel
+// next to a labelled region joins that region, until the whole mask is
+// labelled. Pixels outside the mask keep label 0.
+pub fn watershed(relief: &[f32], mask: &[bool], markers: Labels) -> Labels {
+    let (width, height) = (markers.width as i64, markers.height as i64);
+    let mut labels = markers.labels;
+    let mut queued: Vec<bool> = labels.iter().map(|&label| label != 0).collect();
+
+    // Relief values are quantized so they can be ordered in the heap, the
+    // counter keeps pixels on the same level first in, first out.
+    let level = |index: usize| (relief[index] as f64 * 1024.0).round() as i64;
+    let mut heap = BinaryHeap::new();
+    let mut counter = 0u64;
+
+    let neighbours = |index: usize| {
+        let (x, y) = (index as i64 % width, index as i64 / width);
+        [(1, 0), (-1, 0), (0, 1), (0, -1)]
+            .into_iter()
+            .map(move |(dx, dy)| (x + dx, y + dy))
+            .filter(move |&(nx, ny)| nx >= 0 && ny >= 0 && nx < width && ny < height)
+            .map(move |(nx, ny)| (ny * width + nx) as usize)
+    };
+
+    for (index, &label) in labels.iter().enumerate() {
+        if label == 0 {
+            continue;
+        }
+        for neighbour in neighbours(index) {
+            if !queued[neighbour] && mask[neighbour] {
+                queued[neighbour] = true;
+                heap.push(Reverse((level(neighbour), counter, neighbour, label)));
+                counter += 1;
+            }
+        }
+    }
+
+    while let Some(Reverse((_, _, index, label))) = heap.pop() {
+        labels[index] = label;
+        for neighbour in neighbours(index) {
+            if !queued[neighbour] && mask[neighbour] {
+                queued[neighbour] = true;
+                heap.push(Reverse((level(neighbour), counter, neighbour, label)));
+                counter += 1;
+            }
+        }
+    }
+
+    Labels { width: markers.width, height: markers.height, labels, count: markers.count }
+}
+
+// Frame with the regions tinted in their label colour and the borders
+// between regions drawn in white.
+pub fn overlay(frame: &RgbImage, labels: &Labels) -> RgbImage {
+    let colors = components::colorize(labels);
+
+    ImageBuffer::from_fn(frame.width(), frame.height(), |x, y| {
+        let label = labels.get(x, y);
+        if label == 0 {
+            return *frame.get_pixel(x, y);
+        }
+        let border = [(1i64, 0i64), (0, 1)].iter().any(|&(dx, dy)| {
+            let (nx, ny) = (x as i64 + dx, y as i64 + dy);
+            nx < frame.width() as i64 && ny < frame.height() as i64 && labels.get(nx as u32, ny as u32) != label
+        });
+        if border {
+            return Rgb([255, 255, 255]);
+        }
+
+        let (pixel, color) = (frame.get_pixel(x, y).0, colors.get_pixel(x, y).0);
+        Rgb([0, 1, 2].map(|c| ((pixel[c] as u16 + color[c] as u16) / 2) as u8))
+    })
+}
+
+// Segments the frame and renders the regions as `options.output` asks.
+pub fn draw_watershed(frame: &RgbImage, options: &WatershedOptions) -> Result<RgbImage, Error> {
+    let labels = options.segment(DynamicImage::ImageRgb8(frame.clone()))?;
+    Ok(match options.output {
+        Output::Overlay => overlay(frame, &labels),
+        Output::Labels => components::colorize(&labels),
+    })
+}
+
+impl Filter for WatershedOptions {
+    fn name() -> &'static str { "watershed" }
+    fn label() -> &'static str { "Watershed" }
+
+    fn params() -> Vec<Param> {
+        let defaults = WatershedOptions::default();
+        vec![
+            Param::text("threshold", "otsu", "Binarisation level, or otsu"),
+            Param::bool("invert", defaults.invert, "Segment dark objects on a light background"),
+            Param::int("min_distance", defaults.min_distance, "Minimum distance between markers"),
+            Param::float("min_peak", defaults.min_peak, "Lowest distance transform peak used as a marker"),
+            Param::choice("output", &["overlay", "labels"], "overlay", "Tinted frame or label colours only"),
+        ]
+    }
+
+    fn from_params(params: &HashMap<String, String>) -> Result<WatershedOptions, Error> {
+        WatershedOptions::from_params(params)
+    }
+
+    fn apply(&mut self, frame: &RgbImage) -> Result<RgbImage, Error> {
+        draw_watershed(frame, self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_distance_transform_is_euclidean() {
+        let binary = GrayImage::from_fn(9, 9, |x, y| image::Luma([if (x, y) == (4, 4) { 0 } else { 255 }]));
+        let distance = distance_transform(&binary);
+
+        assert_eq!(distance[4 * 9 + 4], 0.0);
+        assert_eq!(distance[4 * 9 + 7], 3.0);
+        assert!((distance[0] - 32f32.sqrt()).abs() < 1e-5);
+    }
+
+    #[test]
+    pub fn test_watershed_splits_touching_discs() {
+        let discs = [(20.0, 20.0), (36.0, 20.0)];
+        let binary = GrayImage::from_fn(56, 40, |x, y| {
+            let inside = discs.iter().any(|&(cx, cy)| (x as f32 - cx).powi(2) + (y as f32 - cy).powi(2) <= 100.0);
+            image::Luma([if inside { 255 } else { 0 }])
+        });
+
+        // A single connected component before the split.
+        assert_eq!(components::label_components(&binary, Connectivity::Eight).count, 1);
+
+        let labels = WatershedOptions::default().segment(DynamicImage::ImageLuma8(binary.clone())).unwrap();
+
+        assert_eq!(labels.count, 2);
+        let (left, right) = (labels.get(20, 20), labels.get(36, 20));
+        assert!(left != 0 && right != 0 && left != right);
+        for (x, y, pixel) in binary.enumerate_pixels() {
+            assert_eq!(pixel.0[0] > 0, labels.get(x, y) != 0, "({}, {})", x, y);
+        }
+
+        // A window larger than the image only looks at the image.
+        let distance = distance_transform(&binary);
+        assert_eq!(distance_markers(&distance, 56, 40, u32::MAX, 2.0).count, 2);
+    }
+}
@@ -1,5 +1,6 @@
 use crate::gui::index;
-use crate::api::filter;
+use crate::cv::preset;
+use crate::api::{filter, clips, scenes, track, components, features, panorama, circles, probe};
 
 use actix_web::{web, App, HttpServer};
 
@@ -7,13 +8,47 @@ use actix_web::{web, App, HttpServer};
 mod gui;
 mod cv;
 mod api;
+mod cli;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    HttpServer::new(|| {
+    // Any arguments run a command line job instead of the server.
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        if let Err(e) = cli::run(&args) {
+            eprintln!("Error: {}", e);
+            std::process::exit(2);
+        }
+        return Ok(());
+    }
+
+    // Presets are checked once at startup, a broken file stops the server.
+    let presets = preset::load_dir(preset::PRESETS_DIR).unwrap_or_else(|e| {
+        eprintln!("Error loading presets: {}", e);
+        std::process::exit(2);
+    });
+    println!("Loaded {} presets from {}", presets.len(), preset::PRESETS_DIR);
+    let presets = web::Data::new(presets);
+
+    HttpServer::new(move || {
         App::new()
+            .app_data(presets.clone())
             .route("/", web::get().to(index::index))
             .route("/filter", web::post().to(filter::apply_filter))
+            .route("/filter/image", web::post().to(filter::apply_filter_image))
+            .route("/filters", web::get().to(filter::list_filters))
+            .route("/presets", web::get().to(filter::list_presets))
+            .route("/probe", web::post().to(probe::probe_video))
+            .route("/motion/clips", web::post().to(clips::clip_index))
+            .route("/motion/clips/video", web::post().to(clips::extract_clips))
+            .route("/scenes", web::post().to(scenes::scene_index))
+            .route("/scenes/sheet", web::post().to(scenes::contact_sheet))
+            .route("/track", web::post().to(track::track_boxes))
+            .route("/track/video", web::post().to(track::track_video))
+            .route("/components", web::post().to(components::component_stats))
+            .route("/circles", web::post().to(circles::circle_stats))
+            .route("/match", web::post().to(features::match_features))
+            .route("/panorama", web::post().to(panorama::stitch))
     })
     .bind(("127.0.0.1", 8080))?
     .run()